@@ -0,0 +1,43 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crypton_node::block::{NONCE_OFFSET, PAYLOAD_SIZE};
+use crypton_node::crypto::sha256;
+use crypton_node::crypto::sha256::Midstate;
+use crypton_node::util::mrkl_root::calculate_mrkl_root;
+
+fn bench_sha256(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sha256");
+    let payload = [7u8; PAYLOAD_SIZE];
+
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("hash", |b| b.iter(|| sha256::hash(black_box(payload))));
+
+    let mut midstate = Midstate::new(&payload);
+    let mut nonce = 0u64;
+    group.bench_function("midstate", |b| b.iter(|| {
+        nonce += 1;
+        midstate.update(NONCE_OFFSET, &nonce.to_be_bytes());
+        midstate.hash()
+    }));
+
+    group.finish();
+}
+
+fn bench_mrkl_root(c: &mut Criterion) {
+    let mut group = c.benchmark_group("calculate_mrkl_root");
+
+    for count in [1, 16, 256, 4096] {
+        let leaves: Vec<[u8; 32]> = (0..count as u32)
+            .map(|i| sha256::hash(i.to_be_bytes()))
+            .collect();
+
+        group.throughput(Throughput::Elements(count));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &leaves, |b, leaves| {
+            b.iter(|| calculate_mrkl_root(black_box(leaves)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_sha256, bench_mrkl_root);
+criterion_main!(benches);
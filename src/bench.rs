@@ -0,0 +1,215 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::block::{Block, BlockHeader};
+use crate::miner::{MiningJob, Outcome};
+use crate::pow::Pow;
+use crate::util::target::Target;
+
+/// what to benchmark, every miner runs once for every combination of these.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub pow: Pow,
+    /// targets to mine at, as the number of leading zero bits.
+    pub bits: Vec<u32>,
+    pub threads: Vec<usize>,
+    /// how many blocks to mine per run, the time to solution is their average.
+    pub rounds: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let cores = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        let mut threads: Vec<usize> = (0..)
+            .map(|i| 1 << i)
+            .take_while(|n| *n < cores)
+            .collect();
+        threads.push(cores);
+
+        Self {
+            pow: Pow::Sha256,
+            bits: vec![8, 12, 16, 20],
+            threads,
+            rounds: 3,
+        }
+    }
+}
+
+impl Config {
+    /// reads `-pow=sha256 -bits=8,16 -threads=1,4 -rounds=3`, anything left out keeps its default.
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut config = Self::default();
+
+        for arg in args {
+            let (key, value) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("expected -key=value, found {arg}"))?;
+
+            match key {
+                "-pow" => config.pow = value.parse()?,
+                "-bits" => config.bits = parse_list(value)?,
+                "-threads" => config.threads = parse_list(value)?,
+                "-rounds" => config.rounds = value.parse().map_err(|_| format!("invalid rounds: {value}"))?,
+                _ => return Err(format!("unknown option {key}")),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(value: &str) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(|item| item.parse().map_err(|_| format!("invalid value: {item}")))
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub miner: &'static str,
+    pub pow: String,
+    pub bits: u32,
+    pub threads: usize,
+    pub rounds: u32,
+    pub hashes: u64,
+    pub seconds: f64,
+    pub hashrate: f64,
+    /// the average time it took to solve a block, in seconds.
+    pub time_to_solution: f64,
+    /// cpu time of the whole process during the run, none where it can not be read.
+    pub cpu_seconds: Option<f64>,
+    /// cpu time over wall time, 1.0 for every core that was kept busy.
+    pub cpu_usage: Option<f64>,
+}
+
+/// user and system time of this process so far, from `/proc/self/stat`.
+fn cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+
+    // the name in parentheses may contain spaces, the fields after it are space separated
+    // starting with the third, utime and stime are the 14th and 15th.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+
+    // /proc counts in USER_HZ, which is 100 on every linux.
+    Some(Duration::from_millis((utime + stime) * 10))
+}
+
+/// a block that has not been mined yet, different for every round.
+fn template(bits: u32, round: u32) -> BlockHeader {
+    let mut header = Block::genesis().header;
+    header.bits = Target::from_leading_zero_bits(bits).to_compact();
+    header.index = round as u64;
+
+    header
+}
+
+/// the way blocks were mined before the midstate and the parallel miner,
+/// rehashing the whole payload on a single thread.
+fn mine_naive(header: &mut BlockHeader, pow: Pow) -> u64 {
+    let target = Target::from_compact(header.bits);
+    let algorithm = pow.algorithm();
+
+    loop {
+        if target.is_met_by(&header.get_pow_hash(algorithm)) {
+            return header.nonce + 1;
+        }
+
+        header.nonce += 1;
+    }
+}
+
+fn mine_job(header: BlockHeader, pow: Pow, threads: usize) -> u64 {
+    let job = MiningJob::start(header, pow, threads);
+    let progress = job.progress();
+
+    match job.wait(None) {
+        Outcome::Solved(_) => progress.hashes(),
+        outcome => panic!("benchmark stopped without a solution: {outcome:?}"),
+    }
+}
+
+fn measure(miner: &'static str, config: &Config, bits: u32, threads: usize, mut mine: impl FnMut(BlockHeader) -> u64) -> Report {
+    let cpu_start = cpu_time();
+    let start = Instant::now();
+
+    let hashes: u64 = (0..config.rounds)
+        .map(|round| mine(template(bits, round)))
+        .sum();
+
+    let seconds = start.elapsed().as_secs_f64().max(f64::EPSILON);
+    let cpu_seconds = cpu_start
+        .zip(cpu_time())
+        .map(|(start, end)| end.saturating_sub(start).as_secs_f64());
+
+    Report {
+        miner,
+        pow: format!("{:?}", config.pow),
+        bits,
+        threads,
+        rounds: config.rounds,
+        hashes,
+        seconds,
+        hashrate: hashes as f64 / seconds,
+        time_to_solution: seconds / config.rounds.max(1) as f64,
+        cpu_seconds,
+        cpu_usage: cpu_seconds.map(|cpu| cpu / seconds),
+    }
+}
+
+/// mines with every miner at every target and thread count in the config, reporting each run as it finishes.
+pub fn run(config: &Config, mut report: impl FnMut(Report)) {
+    for &bits in &config.bits {
+        report(measure("naive", config, bits, 1, |mut header| mine_naive(&mut header, config.pow)));
+
+        for &threads in &config.threads {
+            report(measure("parallel", config, bits, threads, |header| mine_job(header, config.pow, threads)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_args() {
+        let args = ["-pow=scrypt", "-bits=4,8", "-rounds=1"].map(String::from);
+        let config = Config::from_args(args.into_iter()).unwrap();
+
+        assert_eq!(config.pow, Pow::Scrypt);
+        assert_eq!(config.bits, vec![4, 8]);
+        assert_eq!(config.rounds, 1);
+        assert_eq!(config.threads, Config::default().threads);
+
+        assert!(Config::from_args(["-bits=x".to_string()].into_iter()).is_err());
+        assert!(Config::from_args(["-speed=1".to_string()].into_iter()).is_err());
+        assert!(Config::from_args(["8".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let config = Config {
+            pow: Pow::Sha256,
+            bits: vec![4],
+            threads: vec![1, 2],
+            rounds: 2,
+        };
+
+        let mut reports = Vec::new();
+        run(&config, |report| reports.push(report));
+
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].miner, "naive");
+        assert!(reports.iter().all(|report| report.hashes >= 2 && report.hashrate > 0.0));
+
+        let json: serde_json::Value = serde_json::to_value(&reports[1]).unwrap();
+        assert_eq!(json["miner"], "parallel");
+        assert_eq!(json["threads"], 1);
+    }
+}
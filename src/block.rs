@@ -1,65 +1,226 @@
-use std::fmt::{Debug, Formatter};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
 use crate::crypto::sha256;
-use crate::transaction::Transaction;
+use crate::ledger::LedgerError;
+use crate::params::Params;
+use crate::pow::PowAlgorithm;
+use crate::transaction::{Transaction, TransactionError};
 use crate::util::mrkl_root;
+use crate::util::mrkl_root::MrklBranch;
+use crate::util::target::Target;
 
-const PAYLOAD_SIZE: usize = 96;
+pub const PAYLOAD_SIZE: usize = 92;
+/// where the nonce sits in the payload, the miner only rewrites these 8 bytes.
+pub const NONCE_OFFSET: usize = 84;
 
-pub struct Block<'a> {
-    pub difficulty: u64,
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockError {
+    UnexpectedIndex { expected: u64, found: u64 },
+    ParentMismatch,
+    UnexpectedDifficulty { expected: u32, found: u32 },
+    TimestampTooOld { median: u64, found: u64 },
+    TimestampTooNew { max: u64, found: u64 },
+    MrklRootMismatch,
+    InsufficientWork,
+    InvalidTransaction { position: usize, error: TransactionError },
+    DuplicateTransaction { position: usize },
+    MissingCoinbase,
+    MisplacedCoinbase { position: usize },
+    CoinbaseAmount { expected: u64, found: u64 },
+    CoinbaseHeight { found: u64 },
+    FeeOverflow,
+    Ledger(LedgerError),
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockError::UnexpectedIndex { expected, found } => write!(f, "invalid index: expected {expected}, found {found}"),
+            BlockError::ParentMismatch => write!(f, "parent hash does not match the chain tip"),
+            BlockError::UnexpectedDifficulty { expected, found } => write!(f, "invalid target: expected {expected:#010x}, found {found:#010x}"),
+            BlockError::TimestampTooOld { median, found } => write!(f, "timestamp {found} is not after the median {median}"),
+            BlockError::TimestampTooNew { max, found } => write!(f, "timestamp {found} is after {max}"),
+            BlockError::MrklRootMismatch => write!(f, "merkle root does not match the transactions"),
+            BlockError::InsufficientWork => write!(f, "hash does not meet the target"),
+            BlockError::InvalidTransaction { position, error } => write!(f, "transaction {position} is invalid: {error}"),
+            BlockError::DuplicateTransaction { position } => write!(f, "transaction {position} is listed twice"),
+            BlockError::MissingCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockError::MisplacedCoinbase { position } => write!(f, "transaction {position} is a coinbase"),
+            BlockError::CoinbaseAmount { expected, found } => write!(f, "coinbase pays {found}, expected {expected}"),
+            BlockError::CoinbaseHeight { found } => write!(f, "coinbase is for height {found}"),
+            BlockError::FeeOverflow => write!(f, "total fee overflows"),
+            BlockError::Ledger(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for BlockError {}
+
+/// everything about a block that is hashed, with the transactions only committed to by their merkle root.
+///
+/// small and fixed in size, so it can be hashed, stored and sent around without the transactions.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    /// the target the hash has to meet, in compact form.
+    pub bits: u32,
 
     pub index: u64,
     pub timestamp: u64,
-    pub nonce: u64,
     pub parent_hash: [u8; 32],
+    pub mrkl_root: [u8; 32],
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// the hash blocks are identified and linked by.
+    pub fn get_hash(&self) -> [u8; 32] {
+        sha256::hash(self.get_payload())
+    }
+
+    /// the hash that has to meet the target, the same as `get_hash` unless the chain picked another algorithm.
+    pub fn get_pow_hash(&self, pow: &dyn PowAlgorithm) -> [u8; 32] {
+        pow.hash(&self.get_payload())
+    }
+
+    pub fn get_digest(&self) -> String {
+        sha256::digest(&self.get_hash())
+    }
+
+    pub fn get_payload(&self) -> [u8; PAYLOAD_SIZE] {
+        let mut buffer = [0u8; PAYLOAD_SIZE];
+
+        let bits = self.bits.to_be_bytes();
+        buffer[..4].copy_from_slice(&bits);
+
+        let idx = self.index.to_be_bytes();
+        buffer[4..12].copy_from_slice(&idx);
+
+        let timestamp = self.timestamp.to_be_bytes();
+        buffer[12..20].copy_from_slice(&timestamp);
+
+        let parent_hash = self.parent_hash;
+        buffer[20..52].copy_from_slice(&parent_hash);
 
-    pub transactions: Vec<Transaction<'a>>,
+        let mrkl_root = self.mrkl_root;
+        buffer[52..84].copy_from_slice(&mrkl_root);
+
+        let nonce = self.nonce.to_be_bytes();
+        buffer[NONCE_OFFSET..].copy_from_slice(&nonce);
+
+        buffer
+    }
+
+    /// checks the work, which is all a header can prove on its own.
+    pub fn validate(&self, params: &Params) -> Result<(), BlockError> {
+        if !Target::from_compact(self.bits).is_met_by(&self.get_pow_hash(params.pow.algorithm())) {
+            return Err(BlockError::InsufficientWork);
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for BlockHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockHeader")
+            .field("bits", &format_args!("{:#010x}", self.bits))
+            .field("index", &self.index)
+            .field("timestamp", &self.timestamp)
+            .field("parent_hash", &sha256::digest(&self.parent_hash))
+            .field("mrkl_root", &sha256::digest(&self.mrkl_root))
+            .field("nonce", &self.nonce)
+            .field("hash", &self.get_digest())
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
 }
 
-impl<'a> Block<'a> {
+impl Block {
     pub fn genesis() -> Self {
-        let block = Self {
-            difficulty: 0,
-            index: 0,
-            timestamp: 0,
-            nonce: 0,
-            parent_hash: [0u8; 32],
+        Self {
+            header: BlockHeader {
+                bits: Target::MAX.to_compact(),
+                index: 0,
+                timestamp: 0,
+                parent_hash: [0u8; 32],
+                mrkl_root: mrkl_root::calculate_mrkl_root(&[]),
+                nonce: 0,
+            },
             transactions: Vec::new(),
-        };
-
-        block
+        }
     }
 
     pub fn next(&self) -> Self {
         Self {
-            difficulty: self.difficulty,
-            index: self.index + 1,
-            timestamp: 0,
-            nonce: 0,
-            parent_hash: self.get_hash(),
+            header: BlockHeader {
+                bits: self.header.bits,
+                index: self.header.index + 1,
+                timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+                parent_hash: self.get_hash(),
+                mrkl_root: mrkl_root::calculate_mrkl_root(&[]),
+                nonce: 0,
+            },
             transactions: Vec::new(),
         }
     }
 
-    pub fn add_transaction(&mut self, transaction: &Transaction<'a>) {
+    /// the header is not committed to the transaction until `set_coinbase` or `update_mrkl_root`,
+    /// so filling a block hashes the merkle tree once instead of once per transaction.
+    pub fn add_transaction(&mut self, transaction: &Transaction) {
         self.transactions.push(transaction.clone());
     }
 
-    pub fn get_total_fee(&self) -> u64 {
+    /// puts the coinbase paying the subsidy and the fees of the block to `miner` in front.
+    /// called once all other transactions are added, replacing any earlier coinbase.
+    /// the block is left as it was when the fees can not be paid out in one amount.
+    pub fn set_coinbase(&mut self, miner: &str, params: &Params) -> Result<(), BlockError> {
+        let index = self.header.index;
+        let amount = self.get_total_fee()?
+            .checked_add(params.block_subsidy(index))
+            .ok_or(BlockError::FeeOverflow)?;
+
+        if self.transactions.first().is_some_and(Transaction::is_coinbase) {
+            self.transactions.remove(0);
+        }
+
+        self.transactions.insert(0, Transaction::coinbase(miner, amount, index));
+        self.update_mrkl_root();
+        Ok(())
+    }
+
+    /// commits the header to the transactions, after they were added or changed directly.
+    pub fn update_mrkl_root(&mut self) {
+        self.header.mrkl_root = self.get_mrkl_root();
+    }
+
+    pub fn get_total_fee(&self) -> Result<u64, BlockError> {
         self.transactions
             .iter()
-            .map(|txn| txn.fee)
-            .sum()
+            .try_fold(0u64, |total, txn| total.checked_add(txn.fee))
+            .ok_or(BlockError::FeeOverflow)
     }
 
     pub fn get_hash(&self) -> [u8; 32] {
-        sha256::hash(self.get_payload())
+        self.header.get_hash()
+    }
+
+    pub fn get_pow_hash(&self, pow: &dyn PowAlgorithm) -> [u8; 32] {
+        self.header.get_pow_hash(pow)
     }
 
     pub fn get_digest(&self) -> String {
-        sha256::digest(&self.get_hash())
+        self.header.get_digest()
     }
 
+    /// the merkle root of the transactions as they are now, the header keeps the one it was mined with.
     pub fn get_mrkl_root(&self) -> [u8; 32] {
         let txn_hashes: Vec<[u8; 32]> = self.transactions
             .iter()
@@ -69,48 +230,83 @@ impl<'a> Block<'a> {
         mrkl_root::calculate_mrkl_root(&txn_hashes)
     }
 
+    /// proves the transaction at `position` is in this block, to anyone who has the merkle root.
+    pub fn get_mrkl_branch(&self, position: usize) -> Option<MrklBranch> {
+        let txn_hashes: Vec<[u8; 32]> = self.transactions
+            .iter()
+            .map(|txn| txn.hash)
+            .collect();
+
+        mrkl_root::calculate_mrkl_branch(&txn_hashes, position)
+    }
+
     pub fn get_payload(&self) -> [u8; PAYLOAD_SIZE] {
-        let mut buffer = [0u8; PAYLOAD_SIZE];
+        self.header.get_payload()
+    }
 
-        let difficulty = self.difficulty.to_be_bytes();
-        buffer[..8].copy_from_slice(&difficulty);
+    /// checks everything that can be checked without knowing the rest of the chain.
+    pub fn validate(&self, params: &Params) -> Result<(), BlockError> {
+        self.header.validate(params)?;
 
-        let idx = self.index.to_be_bytes();
-        buffer[8..16].copy_from_slice(&idx);
+        if self.get_mrkl_root() != self.header.mrkl_root {
+            return Err(BlockError::MrklRootMismatch);
+        }
 
-        let timestamp = self.timestamp.to_be_bytes();
-        buffer[16..24].copy_from_slice(&timestamp);
+        // the root commits to the list of hashes, a block listing one twice could be
+        // passed around with the copy or without it.
+        let mut seen = HashSet::with_capacity(self.transactions.len());
 
-        let parent_hash = self.parent_hash;
-        buffer[24..56].copy_from_slice(&parent_hash);
+        for (position, txn) in self.transactions.iter().enumerate() {
+            if !seen.insert(txn.hash) {
+                return Err(BlockError::DuplicateTransaction { position });
+            }
 
-        let mrkl_root = self.get_mrkl_root();
-        buffer[56..88].copy_from_slice(&mrkl_root);
+            txn.verify().map_err(|error| BlockError::InvalidTransaction { position, error })?;
+        }
 
-        let nonce = self.nonce.to_be_bytes();
-        buffer[88..96].copy_from_slice(&nonce);
+        self.validate_coinbase(params)
+    }
 
-        buffer
+    fn validate_coinbase(&self, params: &Params) -> Result<(), BlockError> {
+        let (coinbase, rest) = self.transactions
+            .split_first()
+            .filter(|(coinbase, _)| coinbase.is_coinbase())
+            .ok_or(BlockError::MissingCoinbase)?;
+
+        if let Some(position) = rest.iter().position(Transaction::is_coinbase) {
+            return Err(BlockError::MisplacedCoinbase { position: position + 1 });
+        }
+
+        // otherwise the same coinbase could be paid out again in a later block.
+        if coinbase.nonce != self.header.index {
+            return Err(BlockError::CoinbaseHeight { found: coinbase.nonce });
+        }
+
+        let expected = rest
+            .iter()
+            .try_fold(params.block_subsidy(self.header.index), |total, txn| total.checked_add(txn.fee))
+            .ok_or(BlockError::FeeOverflow)?;
+
+        if coinbase.amount != expected {
+            return Err(BlockError::CoinbaseAmount { expected, found: coinbase.amount });
+        }
+
+        Ok(())
     }
 }
 
-impl<'a> Debug for Block<'a> {
+impl Debug for Block {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Block")
-            .field("difficulty", &self.difficulty)
-            .field("index", &self.index)
-            .field("timestamp", &self.timestamp)
+            .field("header", &self.header)
             .field("transactions", &self.transactions.len())
-            .field("parent_hash", &sha256::digest(&self.parent_hash))
-            .field("mrkl_root", &sha256::digest(&self.get_mrkl_root()))
-            .field("nonce", &self.nonce)
-            .field("hash", &sha256::digest(&self.get_hash()))
             .finish()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::wallet::Wallet;
     use super::*;
 
     #[test]
@@ -118,4 +314,105 @@ mod tests {
         let block = Block::genesis();
         println!("{:#?}", block);
     }
+
+    #[test]
+    fn test_header() {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+
+        let mut block = Block::genesis();
+        let empty = block.header;
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 10, 1, 0));
+        assert_eq!(block.header, empty);
+
+        // the header commits to the transactions, and is all that is hashed.
+        block.update_mrkl_root();
+        assert_eq!(block.header.mrkl_root, block.get_mrkl_root());
+        assert_ne!(block.get_hash(), empty.get_hash());
+        assert_eq!(block.get_hash(), block.header.get_hash());
+
+        let header = rmp_serde::to_vec(&block.header).unwrap();
+        assert_eq!(rmp_serde::from_slice::<BlockHeader>(&header).unwrap(), block.header);
+
+        let next = block.next();
+        assert_eq!(next.header.parent_hash, block.header.get_hash());
+        assert_eq!(next.header.mrkl_root, next.get_mrkl_root());
+    }
+
+    #[test]
+    fn test_coinbase() {
+        let params = Params::default();
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+
+        let mut block = Block::genesis();
+        assert_eq!(block.validate(&params), Err(BlockError::MissingCoinbase));
+
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 10, 3, 0));
+        block.set_coinbase(&bob.address, &params).unwrap();
+        assert_eq!(block.transactions[0].amount, params.block_reward + 3);
+        assert_eq!(block.validate(&params), Ok(()));
+
+        // setting it again replaces the old one.
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 10, 2, 1));
+        block.set_coinbase(&bob.address, &params).unwrap();
+        assert_eq!(block.transactions.len(), 3);
+        assert_eq!(block.validate(&params), Ok(()));
+
+        // fees that can not be paid out in one amount leave the block as it was.
+        let mut overflow = block.clone();
+        overflow.add_transaction(&Transaction::new(&alice, &bob.address, 10, u64::MAX, 2));
+        assert_eq!(overflow.set_coinbase(&bob.address, &params), Err(BlockError::FeeOverflow));
+        assert_eq!(overflow.get_total_fee(), Err(BlockError::FeeOverflow));
+        assert_eq!(overflow.transactions[0], block.transactions[0]);
+        assert_eq!(overflow.header, block.header);
+
+        let mut greedy = block.clone();
+        greedy.transactions[0] = Transaction::coinbase(&bob.address, params.block_reward + 6, 0);
+        assert_eq!(greedy.validate(&params), Err(BlockError::MrklRootMismatch));
+        greedy.update_mrkl_root();
+        assert_eq!(
+            greedy.validate(&params),
+            Err(BlockError::CoinbaseAmount { expected: params.block_reward + 5, found: params.block_reward + 6 })
+        );
+
+        let mut twice = block.clone();
+        twice.add_transaction(&Transaction::coinbase(&bob.address, 0, 0));
+        twice.update_mrkl_root();
+        assert_eq!(twice.validate(&params), Err(BlockError::MisplacedCoinbase { position: 3 }));
+
+        let mut replayed = block.next();
+        replayed.transactions.push(block.transactions[0].clone());
+        replayed.transactions[0].amount = params.block_reward;
+        replayed.transactions[0].hash = sha256::hash(replayed.transactions[0].get_payload());
+        replayed.update_mrkl_root();
+        assert_eq!(replayed.validate(&params), Err(BlockError::CoinbaseHeight { found: 0 }));
+    }
+
+    #[test]
+    fn test_mrkl_branch() {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+
+        let mut block = Block::genesis();
+        for nonce in 0..4 {
+            block.add_transaction(&Transaction::new(&alice, &bob.address, 10, 1, nonce));
+        }
+        block.set_coinbase(&bob.address, &Params::default()).unwrap();
+
+        let root = block.get_mrkl_root();
+        for (position, txn) in block.transactions.iter().enumerate() {
+            let branch = block.get_mrkl_branch(position).unwrap();
+            assert!(branch.verify(&txn.hash, &root));
+        }
+
+        let mut twice = block.clone();
+        twice.add_transaction(&block.transactions[4].clone());
+        twice.update_mrkl_root();
+        assert_eq!(twice.validate(&Params::default()), Err(BlockError::DuplicateTransaction { position: 5 }));
+
+        let branch = block.get_mrkl_branch(1).unwrap();
+        assert!(!branch.verify(&block.transactions[2].hash, &root));
+        assert!(block.get_mrkl_branch(5).is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,757 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use crate::block::{Block, BlockError, BlockHeader};
+use crate::ledger::Ledger;
+use crate::params::Params;
+
+const LOG_FILE: &str = "blocks.dat";
+const EMPTY_HASH: [u8; 32] = [0u8; 32];
+
+#[derive(Debug)]
+pub enum ChainError {
+    Io(io::Error),
+    InvalidBlock { index: u64, error: BlockError },
+    /// a branch has to end up longer than the chain to replace its tip.
+    ShorterBranch { length: u64, current: u64 },
+}
+
+impl Display for ChainError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::Io(e) => write!(f, "io error: {e}"),
+            ChainError::InvalidBlock { index, error } => write!(f, "block {index} rejected: {error}"),
+            ChainError::ShorterBranch { length, current } =>
+                write!(f, "branch of {length} blocks is not longer than the chain of {current}"),
+        }
+    }
+}
+
+impl Error for ChainError {}
+
+impl From<io::Error> for ChainError {
+    fn from(e: io::Error) -> Self {
+        ChainError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    hash: [u8; 32],
+    header: BlockHeader,
+}
+
+/// an append-only block store.
+///
+/// blocks are written to a single log file as length prefixed MessagePack records,
+/// a reorg cuts the log back to the fork and appends the new branch.
+/// the height and hash indexes are kept in memory and rebuilt on `open`,
+/// where every stored block is validated again and applied to the ledger before it is accepted.
+/// the headers are kept in memory too, so they can be served without reading the log.
+pub struct Chain {
+    params: Params,
+    file: File,
+    entries: Vec<Entry>,
+    by_hash: HashMap<[u8; 32], u64>,
+    ledger: Ledger,
+    /// where an incomplete record was cut off the end of the log by `open`.
+    truncated_at: Option<u64>,
+}
+
+impl Chain {
+    pub fn open<P: AsRef<Path>>(dir: P, params: Params) -> Result<Self, ChainError> {
+        std::fs::create_dir_all(&dir)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.as_ref().join(LOG_FILE))?;
+
+        let mut chain = Self {
+            params,
+            file,
+            entries: Vec::new(),
+            by_hash: HashMap::new(),
+            ledger: Ledger::new(),
+            truncated_at: None,
+        };
+
+        chain.load()?;
+
+        Ok(chain)
+    }
+
+    fn load(&mut self) -> Result<(), ChainError> {
+        let len = self.file.metadata()?.len();
+        let mut offset = 0u64;
+
+        while offset < len {
+            let block = match self.read_record(offset) {
+                Ok(block) => block,
+                // a torn write at the end of the log, drop it and keep what was committed.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.file.set_len(offset)?;
+                    self.truncated_at = Some(offset);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            self.connect(&block)?;
+            self.index(&block.header, offset);
+
+            offset = self.file.stream_position()?;
+        }
+
+        Ok(())
+    }
+
+    fn read_record(&self, offset: u64) -> io::Result<Block> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut len = [0u8; 4];
+        file.read_exact(&mut len)?;
+
+        let len = u32::from_be_bytes(len) as u64;
+        if offset + 4 + len > file.metadata()?.len() {
+            // a torn write only cuts the last record short, a whole block in what is left
+            // means the length was corrupted in the middle of the log.
+            let mut rest = Vec::new();
+            file.read_to_end(&mut rest)?;
+
+            if rmp_serde::from_slice::<Block>(&rest).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("record at {offset} is longer than the log"),
+                ));
+            }
+
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut buffer = vec![0u8; len as usize];
+        file.read_exact(&mut buffer)?;
+
+        rmp_serde::from_slice(&buffer)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to deserialize block: {:?}", e)))
+    }
+
+    /// checks a header against the tip, everything about a block that does not need its transactions.
+    pub fn check_header(&self, header: &BlockHeader) -> Result<(), BlockError> {
+        let expected = self.len();
+        if header.index != expected {
+            return Err(BlockError::UnexpectedIndex { expected, found: header.index });
+        }
+
+        if header.parent_hash != self.tip_hash().unwrap_or(EMPTY_HASH) {
+            return Err(BlockError::ParentMismatch);
+        }
+
+        let expected = self.next_bits();
+        if header.bits != expected {
+            return Err(BlockError::UnexpectedDifficulty { expected, found: header.bits });
+        }
+
+        if let Some(median) = self.median_time_past() {
+            if header.timestamp <= median {
+                return Err(BlockError::TimestampTooOld { median, found: header.timestamp });
+            }
+        }
+
+        let max = UNIX_EPOCH.elapsed().unwrap().as_secs() + self.params.max_future_drift;
+        if header.timestamp > max {
+            return Err(BlockError::TimestampTooNew { max, found: header.timestamp });
+        }
+
+        header.validate(&self.params)
+    }
+
+    /// checks the block against the tip and applies it to the ledger.
+    fn connect(&mut self, block: &Block) -> Result<(), ChainError> {
+        let invalid = |error| ChainError::InvalidBlock { index: block.header.index, error };
+
+        self.check_header(&block.header).map_err(invalid)?;
+        block.validate(&self.params).map_err(invalid)?;
+
+        self.ledger
+            .apply_block(block)
+            .map_err(|e| invalid(BlockError::Ledger(e)))
+    }
+
+    fn index(&mut self, header: &BlockHeader, offset: u64) {
+        let hash = header.get_hash();
+        self.by_hash.insert(hash, header.index);
+        self.entries.push(Entry {
+            offset,
+            hash,
+            header: *header,
+        });
+    }
+
+    /// the compact target the next block has to have.
+    ///
+    /// it stays the same as the tip's, except for the first block of every retarget window,
+    /// where it is adjusted by how long the previous window took.
+    pub fn next_bits(&self) -> u32 {
+        let Some(tip) = self.entries.last() else {
+            return self.params.initial_bits;
+        };
+
+        let height = self.len();
+        let interval = self.params.retarget_interval.max(2);
+
+        if !height.is_multiple_of(interval) {
+            return tip.header.bits;
+        }
+
+        let first = &self.entries[(height - interval) as usize];
+        let timespan = tip.header.timestamp.saturating_sub(first.header.timestamp);
+
+        self.params.retarget(tip.header.bits, timespan)
+    }
+
+    /// the median timestamp of the last `median_time_span` blocks, the next block has to be newer.
+    pub fn median_time_past(&self) -> Option<u64> {
+        let span = self.params.median_time_span.max(1);
+        let start = self.entries.len().saturating_sub(span);
+
+        let mut timestamps: Vec<u64> = self.entries[start..]
+            .iter()
+            .map(|entry| entry.header.timestamp)
+            .collect();
+
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied()
+    }
+
+    /// an empty block on top of the tip, with the target and a timestamp the chain will accept.
+    pub fn next_block(&self) -> Block {
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let timestamp = match self.median_time_past() {
+            Some(median) => now.max(median + 1),
+            None => now,
+        };
+
+        let mut block = Block::genesis();
+        block.header.bits = self.next_bits();
+        block.header.index = self.len();
+        block.header.timestamp = timestamp;
+        block.header.parent_hash = self.tip_hash().unwrap_or(EMPTY_HASH);
+
+        block
+    }
+
+    /// validates the block against the current tip and appends it to the log.
+    pub fn append(&mut self, block: &Block) -> Result<(), ChainError> {
+        self.connect(block)?;
+
+        match self.write_record(block) {
+            Ok(offset) => {
+                self.index(&block.header, offset);
+                Ok(())
+            }
+            Err(e) => {
+                self.ledger
+                    .revert_block(block)
+                    .expect("a block that was just applied can be reverted");
+                Err(e.into())
+            }
+        }
+    }
+
+    /// takes the tip off the chain, out of the log and out of the ledger.
+    pub fn disconnect_tip(&mut self) -> Result<Option<Block>, ChainError> {
+        let Some(entry) = self.entries.last().copied() else {
+            return Ok(None);
+        };
+
+        let block = self.read_record(entry.offset)?;
+
+        self.ledger
+            .revert_block(&block)
+            .map_err(|e| ChainError::InvalidBlock { index: entry.header.index, error: BlockError::Ledger(e) })?;
+
+        if let Err(e) = self.file.set_len(entry.offset).and_then(|_| self.file.sync_data()) {
+            self.ledger
+                .apply_block(&block)
+                .expect("a block that was just reverted can be applied again");
+            return Err(e.into());
+        }
+
+        self.entries.pop();
+        self.by_hash.remove(&entry.hash);
+
+        Ok(Some(block))
+    }
+
+    /// replaces the blocks from the height of the first block of `branch` on with the branch,
+    /// if that makes the chain longer. returns the blocks that were disconnected, oldest first.
+    ///
+    /// when a block of the branch is rejected, the branch is taken off again
+    /// and the old blocks are connected back.
+    pub fn reorganize(&mut self, branch: &[Block]) -> Result<Vec<Block>, ChainError> {
+        let Some(first) = branch.first() else {
+            return Ok(Vec::new());
+        };
+
+        let fork = first.header.index;
+        let invalid = |error| ChainError::InvalidBlock { index: fork, error };
+
+        if fork > self.len() {
+            return Err(invalid(BlockError::UnexpectedIndex { expected: self.len(), found: fork }));
+        }
+
+        let parent = match fork {
+            0 => EMPTY_HASH,
+            height => self.entries[height as usize - 1].hash,
+        };
+
+        if first.header.parent_hash != parent {
+            return Err(invalid(BlockError::ParentMismatch));
+        }
+
+        let length = fork + branch.len() as u64;
+        if length <= self.len() {
+            return Err(ChainError::ShorterBranch { length, current: self.len() });
+        }
+
+        let disconnected = self.truncate(fork)?;
+
+        for block in branch {
+            if let Err(e) = self.append(block) {
+                self.truncate(fork)?;
+
+                for block in &disconnected {
+                    self.append(block)?;
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(disconnected)
+    }
+
+    /// disconnects blocks until the chain is `height` blocks long, returns them oldest first.
+    fn truncate(&mut self, height: u64) -> Result<Vec<Block>, ChainError> {
+        let mut disconnected = Vec::new();
+
+        while self.len() > height {
+            match self.disconnect_tip()? {
+                Some(block) => disconnected.push(block),
+                None => break,
+            }
+        }
+
+        disconnected.reverse();
+        Ok(disconnected)
+    }
+
+    fn write_record(&mut self, block: &Block) -> io::Result<u64> {
+        let buffer = rmp_serde::to_vec(block)
+            .map_err(|e| io::Error::other(format!("Failed to serialize block: {:?}", e)))?;
+
+        let offset = self.file.metadata()?.len();
+
+        let mut data = Vec::with_capacity(4 + buffer.len());
+        data.extend_from_slice(&(buffer.len() as u32).to_be_bytes());
+        data.extend_from_slice(&buffer);
+
+        self.file.write_all(&data)?;
+        self.file.sync_data()?;
+
+        Ok(offset)
+    }
+
+    /// the offset an incomplete record was dropped from when the log was opened, if there was one.
+    pub fn truncated_at(&self) -> Option<u64> {
+        self.truncated_at
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// the balances after every block in the chain.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn tip_hash(&self) -> Option<[u8; 32]> {
+        self.entries.last().map(|entry| entry.hash)
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    pub fn get_by_height(&self, height: u64) -> Result<Option<Block>, ChainError> {
+        match self.entries.get(height as usize) {
+            Some(entry) => Ok(Some(self.read_record(entry.offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// the header of the block at `height`, without reading the log.
+    pub fn get_header(&self, height: u64) -> Option<BlockHeader> {
+        self.entries
+            .get(height as usize)
+            .map(|entry| entry.header)
+    }
+
+    pub fn get_by_hash(&self, hash: &[u8; 32]) -> Result<Option<Block>, ChainError> {
+        match self.by_hash.get(hash) {
+            Some(height) => self.get_by_height(*height),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::target::Target;
+    use crate::ledger::LedgerError;
+    use crate::miner;
+    use crate::pow::Pow;
+    use crate::test_util::{mine_block, mine_blocks, params, temp_dir};
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallet;
+    use super::*;
+
+    #[test]
+    fn test_append_and_reload() {
+        let dir = temp_dir();
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+
+        let mut chain = Chain::open(&dir, params()).unwrap();
+        for i in 0..5 {
+            mine_block(&mut chain, &[Transaction::new(&alice, &bob.address, i, 1, i)]);
+        }
+        let tip = chain.tip_hash().unwrap();
+        drop(chain);
+
+        let chain = Chain::open(&dir, params()).unwrap();
+        assert_eq!(chain.len(), 5);
+        assert_eq!(chain.tip_hash(), Some(tip));
+        assert_eq!(chain.truncated_at(), None);
+
+        // 5 rewards and 5 fees for alice, 0 + 1 + 2 + 3 + 4 for bob.
+        assert_eq!(chain.ledger().balance(&alice.address), 5 * chain.params().block_reward - 10);
+        assert_eq!(chain.ledger().balance(&bob.address), 10);
+
+        let block = chain.get_by_hash(&tip).unwrap().unwrap();
+        assert_eq!(block.header.index, 4);
+        assert_eq!(block.transactions.len(), 2);
+        assert_eq!(chain.get_by_height(4).unwrap().unwrap().get_hash(), tip);
+        assert!(chain.get_by_height(5).unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_invalid_blocks() {
+        let dir = temp_dir();
+        let mut chain = Chain::open(&dir, params()).unwrap();
+
+        let mut block = chain.next_block();
+        block.header.index = 1;
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::UnexpectedIndex { expected: 0, found: 1 }, .. })
+        ));
+
+        let mut block = chain.next_block();
+        block.header.parent_hash = [1u8; 32];
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::ParentMismatch, .. })
+        ));
+
+        let mut block = chain.next_block();
+        block.header.bits = Target::from_leading_zero_bits(32).to_compact();
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::UnexpectedDifficulty { .. }, .. })
+        ));
+
+        let mut block = chain.next_block();
+        while Target::from_compact(block.header.bits).is_met_by(&block.get_hash()) {
+            block.header.nonce += 1;
+        }
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::InsufficientWork, .. })
+        ));
+
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+
+        let mut block = chain.next_block();
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 10, 1, 0));
+        block.set_coinbase(&bob.address, chain.params()).unwrap();
+        miner::mine(&mut block, chain.params().pow);
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::Ledger(LedgerError::Overdraft { .. }), .. })
+        ));
+
+        assert!(chain.is_empty());
+        assert_eq!(chain.ledger().balance(&bob.address), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_invalid_timestamps() {
+        let dir = temp_dir();
+        let params = Params {
+            initial_bits: Target::MAX.to_compact(),
+            median_time_span: 3,
+            ..Params::default()
+        };
+        let mut chain = Chain::open(&dir, params).unwrap();
+        let alice = Wallet::from_passphrase("alice");
+
+        for timestamp in [100, 200, 300] {
+            let mut block = chain.next_block();
+            block.header.timestamp = timestamp;
+            block.set_coinbase(&alice.address, chain.params()).unwrap();
+            chain.append(&block).unwrap();
+        }
+
+        assert_eq!(chain.median_time_past(), Some(200));
+
+        let mut block = chain.next_block();
+        block.header.timestamp = 200;
+        block.set_coinbase(&alice.address, chain.params()).unwrap();
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::TimestampTooOld { median: 200, found: 200 }, .. })
+        ));
+
+        block.header.timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() + chain.params().max_future_drift + 60;
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::TimestampTooNew { .. }, .. })
+        ));
+
+        // older than the tip is fine, as long as it is newer than the median.
+        block.header.timestamp = 201;
+        chain.append(&block).unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_retarget() {
+        let dir = temp_dir();
+        let params = Params {
+            initial_bits: Target::MAX.to_compact(),
+            retarget_interval: 4,
+            ..Params::default()
+        };
+        let limit = params.pow_limit;
+        let mut chain = Chain::open(&dir, params).unwrap();
+        let alice = Wallet::from_passphrase("alice");
+
+        // 4 blocks within a few seconds, far faster than a minute apart.
+        for _ in 0..4 {
+            let mut block = chain.next_block();
+            block.set_coinbase(&alice.address, chain.params()).unwrap();
+            chain.append(&block).unwrap();
+            assert_eq!(chain.len() < 4, chain.next_bits() == limit);
+        }
+
+        // at most 4 times harder in one window.
+        let expected = Target::from_compact(limit).mul_div(1, 4).to_compact();
+        assert_eq!(chain.next_bits(), expected);
+
+        let mut block = chain.next_block();
+        block.header.bits = limit;
+        block.set_coinbase(&alice.address, chain.params()).unwrap();
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::UnexpectedDifficulty { expected: e, found: f }, .. })
+                if e == expected && f == limit
+        ));
+
+        block.header.bits = expected;
+        miner::mine(&mut block, chain.params().pow);
+        chain.append(&block).unwrap();
+        assert_eq!(chain.next_bits(), expected);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pow_algorithm() {
+        let dir = temp_dir();
+        let params = Params {
+            pow: Pow::Scrypt,
+            initial_bits: Target::from_leading_zero_bits(16).to_compact(),
+            ..Params::default()
+        };
+        let mut chain = Chain::open(&dir, params).unwrap();
+        let alice = Wallet::from_passphrase("alice");
+
+        // enough work for sha256, but this chain hashes with scrypt.
+        let mut block = chain.next_block();
+        block.set_coinbase(&alice.address, chain.params()).unwrap();
+        miner::mine(&mut block, Pow::Sha256);
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::InvalidBlock { error: BlockError::InsufficientWork, .. })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_rejects_tampered_log() {
+        let dir = temp_dir();
+
+        let mut chain = Chain::open(&dir, params()).unwrap();
+        mine_blocks(&mut chain, 3);
+        let offset = chain.entries[1].offset;
+        let block = chain.read_record(offset).unwrap();
+        drop(chain);
+
+        // change a transaction hash in the second block, its header still commits to the old one.
+        let encoded = rmp_serde::to_vec(&block).unwrap();
+        let mut tampered = block;
+        tampered.transactions[0].hash[0] ^= 0x01;
+        let tampered = rmp_serde::to_vec(&tampered).unwrap();
+        assert_eq!(encoded.len(), tampered.len());
+
+        let mut log = std::fs::read(dir.join(LOG_FILE)).unwrap();
+        let start = offset as usize + 4;
+        log[start..start + tampered.len()].copy_from_slice(&tampered);
+        std::fs::write(dir.join(LOG_FILE), log).unwrap();
+
+        assert!(matches!(
+            Chain::open(&dir, params()),
+            Err(ChainError::InvalidBlock { index: 1, error: BlockError::MrklRootMismatch })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_truncates_torn_write() {
+        let dir = temp_dir();
+
+        let mut chain = Chain::open(&dir, params()).unwrap();
+        mine_blocks(&mut chain, 2);
+        drop(chain);
+
+        let mut log = std::fs::read(dir.join(LOG_FILE)).unwrap();
+        let len = log.len();
+        log.extend_from_slice(&[0, 0, 1, 0, 0xde, 0xad]);
+        std::fs::write(dir.join(LOG_FILE), log).unwrap();
+
+        let chain = Chain::open(&dir, params()).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain.truncated_at(), Some(len as u64));
+        assert_eq!(std::fs::metadata(dir.join(LOG_FILE)).unwrap().len(), len as u64);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_rejects_corrupted_length() {
+        let dir = temp_dir();
+
+        let mut chain = Chain::open(&dir, params()).unwrap();
+        mine_blocks(&mut chain, 3);
+        let offset = chain.entries[1].offset as usize;
+        drop(chain);
+
+        // the second record claims more than is left of the log.
+        let mut log = std::fs::read(dir.join(LOG_FILE)).unwrap();
+        let len = log.len();
+        log[offset..offset + 4].copy_from_slice(&(len as u32).to_be_bytes());
+        std::fs::write(dir.join(LOG_FILE), log).unwrap();
+
+        assert!(matches!(
+            Chain::open(&dir, params()),
+            Err(ChainError::Io(e)) if e.kind() == io::ErrorKind::InvalidData
+        ));
+        assert_eq!(std::fs::metadata(dir.join(LOG_FILE)).unwrap().len(), len as u64);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let (dir, other_dir) = (temp_dir(), temp_dir());
+        let mut chain = Chain::open(&dir, params()).unwrap();
+        mine_blocks(&mut chain, 3);
+        let old = [chain.get_header(1).unwrap().get_hash(), chain.get_header(2).unwrap().get_hash()];
+        let balance = chain.ledger().balance(&Wallet::from_passphrase("bob").address);
+
+        // a branch off the first block, one block longer, paying bob instead.
+        let bob = Wallet::from_passphrase("bob");
+        let mut other = Chain::open(&other_dir, params()).unwrap();
+        other.append(&chain.get_by_height(0).unwrap().unwrap()).unwrap();
+
+        let mut branch = Vec::new();
+        for _ in 0..3 {
+            let mut block = other.next_block();
+            block.set_coinbase(&bob.address, other.params()).unwrap();
+            miner::mine(&mut block, other.params().pow);
+            other.append(&block).unwrap();
+            branch.push(block);
+        }
+
+        assert!(matches!(
+            chain.reorganize(&branch[..2]),
+            Err(ChainError::ShorterBranch { length: 3, current: 3 })
+        ));
+        assert!(matches!(
+            chain.reorganize(&branch[1..]),
+            Err(ChainError::InvalidBlock { index: 2, error: BlockError::ParentMismatch })
+        ));
+
+        // a branch with a bad block is taken off again, the old blocks are back.
+        let mut bad = branch.clone();
+        while Target::from_compact(bad[2].header.bits).is_met_by(&bad[2].get_hash()) {
+            bad[2].header.nonce += 1;
+        }
+        assert!(chain.reorganize(&bad).is_err());
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain.tip_hash(), Some(old[1]));
+        assert_eq!(chain.ledger().balance(&bob.address), balance);
+
+        let disconnected = chain.reorganize(&branch).unwrap();
+        assert_eq!(disconnected.iter().map(Block::get_hash).collect::<Vec<_>>(), old);
+        assert_eq!(chain.len(), 4);
+        assert_eq!(chain.tip_hash(), other.tip_hash());
+        assert_eq!(chain.ledger().balance(&bob.address), other.ledger().balance(&bob.address));
+
+        // the log was cut back too.
+        drop(chain);
+        let chain = Chain::open(&dir, params()).unwrap();
+        assert_eq!(chain.tip_hash(), other.tip_hash());
+        assert!(!chain.contains(&old[1]));
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(other_dir).unwrap();
+    }
+}
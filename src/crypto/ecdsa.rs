@@ -1,9 +1,12 @@
 use crate::crypto::sha256;
 use hex::encode;
 use std::fmt::{Debug, Formatter};
-use secp256k1::{All, Message};
+use once_cell::sync::Lazy;
+use secp256k1::{All, Message, PublicKey, Secp256k1, VerifyOnly};
 use secp256k1::ecdsa::Signature;
 
+static VERIFIER: Lazy<Secp256k1<VerifyOnly>> = Lazy::new(Secp256k1::verification_only);
+
 pub struct Ecdsa {
     _curve: secp256k1::Secp256k1<All>,
     pub s_key: secp256k1::SecretKey,
@@ -12,13 +15,19 @@ pub struct Ecdsa {
 
 impl Debug for Ecdsa {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let s_key = encode(&self.s_key.secret_bytes());
-        let p_key = encode(&self.p_key.serialize());
+        let s_key = encode(self.s_key.secret_bytes());
+        let p_key = encode(self.p_key.serialize());
 
         write!(f, "Ecdsa S: {} P: {}", s_key, p_key)
     }
 }
 
+impl Default for Ecdsa {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ecdsa {
     pub fn new() -> Self {
         let curve = secp256k1::Secp256k1::new();
@@ -55,6 +64,14 @@ impl Ecdsa {
     }
 }
 
+/// verifies a signature against a serialized public key, for when there is no secret key at hand.
+pub fn verify(p_key: &[u8], msg: &Message, sig: &Signature) -> bool {
+    match PublicKey::from_slice(p_key) {
+        Ok(p_key) => VERIFIER.verify_ecdsa(msg, sig, &p_key).is_ok(),
+        Err(_) => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,7 +81,7 @@ mod tests {
         let ecdsa = Ecdsa::new();
 
         let payload = b"aaa";
-        let msg = Message::from_digest(sha256::hash(&payload));
+        let msg = Message::from_digest(sha256::hash(payload));
         let signature = ecdsa.sign(&msg);
 
         assert!(ecdsa.verify(&msg, &signature));
@@ -73,10 +90,10 @@ mod tests {
     #[test]
     fn test_from_passphrase() {
         let passphrase = "correct horse battery staple";
-        let ecdsa = Ecdsa::from_passphrase(&passphrase);
+        let ecdsa = Ecdsa::from_passphrase(passphrase);
 
         let payload = b"aaa";
-        let msg = Message::from_digest(sha256::hash(&payload));
+        let msg = Message::from_digest(sha256::hash(payload));
         let signature = ecdsa.sign(&msg);
 
         assert!(ecdsa.verify(&msg, &signature));
@@ -87,9 +104,23 @@ mod tests {
         let ecdsa = Ecdsa::new();
 
         let payload = b"aaa";
-        let msg = Message::from_digest(sha256::hash(&payload));
+        let msg = Message::from_digest(sha256::hash(payload));
         let signature = ecdsa.sign(&msg);
 
         assert!(ecdsa.verify(&msg, &signature));
     }
+
+    #[test]
+    fn test_verify_public_key() {
+        let ecdsa = Ecdsa::new();
+        let other = Ecdsa::new();
+
+        let msg = Message::from_digest(sha256::hash(b"aaa"));
+        let signature = ecdsa.sign(&msg);
+
+        assert!(verify(&ecdsa.p_key.serialize(), &msg, &signature));
+        assert!(verify(&ecdsa.p_key.serialize_uncompressed(), &msg, &signature));
+        assert!(!verify(&other.p_key.serialize(), &msg, &signature));
+        assert!(!verify(&[0u8; 33], &msg, &signature));
+    }
 }
\ No newline at end of file
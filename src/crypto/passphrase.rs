@@ -2,11 +2,12 @@ use chbs::{config::BasicConfig, prelude::*};
 use chbs::probability::Probability;
 
 pub fn passphrase(count: usize) -> String {
-    let mut config = BasicConfig::default();
-    config.words = count;
-
-    config.capitalize_first = Probability::Never;
-    config.capitalize_words = Probability::Never;
+    let config = BasicConfig {
+        words: count,
+        capitalize_first: Probability::Never,
+        capitalize_words: Probability::Never,
+        ..Default::default()
+    };
 
     config.to_scheme().generate()
 }
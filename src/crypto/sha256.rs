@@ -1,5 +1,14 @@
 use hex::encode;
 use sha2::{Sha256, Digest};
+use sha2::digest::generic_array::GenericArray;
+
+const BLOCK_SIZE: usize = 64;
+
+/// the initial hash value from FIPS 180-4.
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
 
 pub fn hash<T: AsRef<[u8]>>(data: T) -> [u8; 32] {
     let mut hasher = Sha256::new();
@@ -16,6 +25,75 @@ pub fn digest(hash: &[u8; 32]) -> String {
     encode(hash)
 }
 
+/// hashes a message of a fixed length where only the bytes after the first 64 change,
+/// like a block payload while the nonce is searched.
+///
+/// the state after the first 64 byte blocks is computed once,
+/// so every hash after that is a single compression of the padded last block.
+/// sha2 picks the SHA-NI or ARMv8 instructions at runtime where the CPU has them.
+#[derive(Clone)]
+pub struct Midstate {
+    state: [u32; 8],
+    /// where the last block starts in the message.
+    offset: usize,
+    /// the length of the message, the padding comes after it.
+    len: usize,
+    last: [u8; BLOCK_SIZE],
+}
+
+impl Midstate {
+    /// panics if the rest of the message after its full blocks does not fit in one block with the padding.
+    pub fn new(data: &[u8]) -> Self {
+        let offset = data.len() / BLOCK_SIZE * BLOCK_SIZE;
+        let rest = &data[offset..];
+        assert!(rest.len() + 9 <= BLOCK_SIZE, "the message does not end in a single padded block");
+
+        let mut state = IV;
+        let blocks: Vec<_> = data[..offset]
+            .chunks_exact(BLOCK_SIZE)
+            .map(GenericArray::clone_from_slice)
+            .collect();
+        sha2::compress256(&mut state, &blocks);
+
+        // the padding: a single 1 bit, zeros, then the length of the message in bits.
+        let mut last = [0u8; BLOCK_SIZE];
+        last[..rest.len()].copy_from_slice(rest);
+        last[rest.len()] = 0x80;
+        last[BLOCK_SIZE - 8..].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+        Self { state, offset, len: data.len(), last }
+    }
+
+    /// overwrites the message at `position`.
+    ///
+    /// panics if the bytes are not all in the last block, the hash of the blocks before it is fixed,
+    /// or if they reach past the end of the message into the padding.
+    pub fn update(&mut self, position: usize, bytes: &[u8]) {
+        assert!(
+            position >= self.offset && position + bytes.len() <= self.len,
+            "bytes {position}..{} are not in the last block of the message, {}..{}",
+            position + bytes.len(),
+            self.offset,
+            self.len,
+        );
+
+        let start = position - self.offset;
+        self.last[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        let mut state = self.state;
+        sha2::compress256(&mut state, std::slice::from_ref(GenericArray::from_slice(&self.last)));
+
+        let mut bytes = [0u8; 32];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+
+        bytes
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -26,7 +104,7 @@ mod tests {
         let expected = "9834876dcfb05cb167a5c24953eba58c4ac89b1adf57f28f2f9d09af107ee8f0";
 
         let hash = hash(data.as_bytes());
-        let hash_hex = encode(&hash);
+        let hash_hex = encode(hash);
 
         assert_eq!(hash_hex, expected);
     }
@@ -41,4 +119,37 @@ mod tests {
 
         assert_eq!(digest, expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_midstate() {
+        for len in [0, 1, 55, 64, 92, 119] {
+            let mut data: Vec<u8> = (0..len as u8).collect();
+            let mut midstate = Midstate::new(&data);
+            assert_eq!(midstate.hash(), hash(&data));
+
+            if len > 64 {
+                data[len - 1] = 0xff;
+                midstate.update(len - 1, &[0xff]);
+                assert_eq!(midstate.hash(), hash(&data));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_midstate_too_long() {
+        Midstate::new(&[0u8; 120]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not in the last block")]
+    fn test_midstate_update_before_last_block() {
+        Midstate::new(&[0u8; 92]).update(60, &[1u8; 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not in the last block")]
+    fn test_midstate_update_past_end() {
+        Midstate::new(&[0u8; 92]).update(88, &[1u8; 8]);
+    }
+}
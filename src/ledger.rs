@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::block::Block;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    /// the nonce the next transaction from this account has to carry.
+    pub nonce: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LedgerError {
+    Overdraft { position: usize, address: String, balance: u64, required: u64 },
+    Overflow { position: usize },
+    InvalidNonce { position: usize, expected: u64, found: u64 },
+    /// reverting would take back money that is not there, so the block was never applied.
+    NotApplied,
+}
+
+impl Display for LedgerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerError::Overdraft { position, address, balance, required } =>
+                write!(f, "transaction {position} overdraws {address}: balance {balance}, required {required}"),
+            LedgerError::Overflow { position } => write!(f, "transaction {position} overflows a balance"),
+            LedgerError::InvalidNonce { position, expected, found } =>
+                write!(f, "transaction {position} has nonce {found}, expected {expected}"),
+            LedgerError::NotApplied => write!(f, "block was not applied to this ledger"),
+        }
+    }
+}
+
+impl Error for LedgerError {}
+
+/// the balance of every address, built by applying blocks in order.
+///
+/// a block is applied all or nothing, the changes are collected first
+/// and only written back once every transaction in the block went through.
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    accounts: HashMap<String, Account>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a ledger with some addresses already funded, for the genesis allocation.
+    pub fn with_balances<I: IntoIterator<Item = (String, u64)>>(balances: I) -> Self {
+        let accounts = balances
+            .into_iter()
+            .map(|(address, balance)| (address, Account { balance, nonce: 0 }))
+            .collect();
+
+        Self { accounts }
+    }
+
+    pub fn account(&self, address: &str) -> Account {
+        self.accounts
+            .get(address)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.account(address).balance
+    }
+
+    pub fn nonce(&self, address: &str) -> u64 {
+        self.account(address).nonce
+    }
+
+    /// applies the block's transactions in order.
+    ///
+    /// every transaction has to carry the next nonce of its sender,
+    /// so a transaction that was already applied can not be applied again.
+    ///
+    /// the fees are not credited here, the coinbase already pays them to the miner
+    /// and the block checks it pays no more than that.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), LedgerError> {
+        let mut changes = Changes::new(self);
+
+        for (position, txn) in block.transactions.iter().enumerate() {
+            if txn.is_coinbase() {
+                changes.credit(&txn.recipient, txn.amount, position)?;
+                continue;
+            }
+
+            let required = txn.amount
+                .checked_add(txn.fee)
+                .ok_or(LedgerError::Overflow { position })?;
+
+            let sender = changes.get(&txn.sender);
+
+            if txn.nonce != sender.nonce {
+                return Err(LedgerError::InvalidNonce { position, expected: sender.nonce, found: txn.nonce });
+            }
+
+            let balance = sender.balance;
+            sender.balance = balance
+                .checked_sub(required)
+                .ok_or_else(|| LedgerError::Overdraft { position, address: txn.sender.clone(), balance, required })?;
+            sender.nonce += 1;
+
+            changes.credit(&txn.recipient, txn.amount, position)?;
+        }
+
+        changes.commit();
+        Ok(())
+    }
+
+    /// undoes `apply_block`, for when the block is disconnected during a reorg.
+    pub fn revert_block(&mut self, block: &Block) -> Result<(), LedgerError> {
+        let mut changes = Changes::new(self);
+
+        for txn in block.transactions.iter().rev() {
+            changes.debit(&txn.recipient, txn.amount)?;
+
+            if txn.is_coinbase() {
+                continue;
+            }
+
+            let account = changes.get(&txn.sender);
+
+            if txn.nonce.checked_add(1) != Some(account.nonce) {
+                return Err(LedgerError::NotApplied);
+            }
+
+            account.nonce -= 1;
+            account.balance = account.balance
+                .checked_add(txn.amount)
+                .and_then(|balance| balance.checked_add(txn.fee))
+                .ok_or(LedgerError::NotApplied)?;
+        }
+
+        changes.commit();
+        Ok(())
+    }
+}
+
+/// the accounts touched by a block, on top of the ledger they are written back to.
+struct Changes<'a> {
+    ledger: &'a mut Ledger,
+    accounts: HashMap<String, Account>,
+}
+
+impl<'a> Changes<'a> {
+    fn new(ledger: &'a mut Ledger) -> Self {
+        Self {
+            ledger,
+            accounts: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, address: &str) -> &mut Account {
+        if !self.accounts.contains_key(address) {
+            let account = self.ledger.account(address);
+            self.accounts.insert(address.to_string(), account);
+        }
+
+        self.accounts.get_mut(address).unwrap()
+    }
+
+    fn credit(&mut self, address: &str, amount: u64, position: usize) -> Result<(), LedgerError> {
+        let account = self.get(address);
+        account.balance = account.balance
+            .checked_add(amount)
+            .ok_or(LedgerError::Overflow { position })?;
+
+        Ok(())
+    }
+
+    fn debit(&mut self, address: &str, amount: u64) -> Result<(), LedgerError> {
+        let account = self.get(address);
+        account.balance = account.balance
+            .checked_sub(amount)
+            .ok_or(LedgerError::NotApplied)?;
+
+        Ok(())
+    }
+
+    fn commit(self) {
+        for (address, account) in self.accounts {
+            if account == Account::default() {
+                self.ledger.accounts.remove(&address);
+            } else {
+                self.ledger.accounts.insert(address, account);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::Params;
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallet;
+    use super::*;
+
+    fn params() -> Params {
+        Params {
+            block_reward: 10,
+            halving_interval: 100,
+            ..Params::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_block() {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+        let miner = Wallet::from_passphrase("miner");
+
+        let mut ledger = Ledger::with_balances([(alice.address.clone(), 100)]);
+
+        let mut block = Block::genesis();
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 60, 2, 0));
+        block.add_transaction(&Transaction::new(&bob, &alice.address, 10, 1, 0));
+        block.set_coinbase(&miner.address, &params()).unwrap();
+
+        ledger.apply_block(&block).unwrap();
+
+        assert_eq!(ledger.balance(&alice.address), 48);
+        assert_eq!(ledger.balance(&bob.address), 49);
+        assert_eq!(ledger.balance(&miner.address), 13);
+        assert_eq!(ledger.nonce(&alice.address), 1);
+        assert_eq!(ledger.nonce(&bob.address), 1);
+    }
+
+    #[test]
+    fn test_reject_replay() {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+        let miner = Wallet::from_passphrase("miner");
+
+        let mut ledger = Ledger::with_balances([(alice.address.clone(), 100)]);
+        let txn = Transaction::new(&alice, &bob.address, 10, 1, 0);
+
+        let mut first = Block::genesis();
+        first.add_transaction(&txn);
+        first.set_coinbase(&miner.address, &params()).unwrap();
+        ledger.apply_block(&first).unwrap();
+
+        // the same signed transaction broadcast again.
+        let mut replay = first.next();
+        replay.add_transaction(&txn);
+        replay.set_coinbase(&miner.address, &params()).unwrap();
+        assert_eq!(
+            ledger.apply_block(&replay),
+            Err(LedgerError::InvalidNonce { position: 1, expected: 1, found: 0 })
+        );
+
+        let mut skipped = first.next();
+        skipped.add_transaction(&Transaction::new(&alice, &bob.address, 10, 1, 2));
+        skipped.set_coinbase(&miner.address, &params()).unwrap();
+        assert_eq!(
+            ledger.apply_block(&skipped),
+            Err(LedgerError::InvalidNonce { position: 1, expected: 1, found: 2 })
+        );
+
+        assert_eq!(ledger.balance(&bob.address), 10);
+        assert_eq!(ledger.nonce(&alice.address), 1);
+    }
+
+    #[test]
+    fn test_spend_coinbase() {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+
+        let mut ledger = Ledger::new();
+
+        let mut block = Block::genesis();
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 5, 1, 0));
+        block.set_coinbase(&alice.address, &params()).unwrap();
+
+        ledger.apply_block(&block).unwrap();
+
+        assert_eq!(ledger.balance(&alice.address), 5);
+        assert_eq!(ledger.balance(&bob.address), 5);
+    }
+
+    #[test]
+    fn test_reject_overdraft() {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+        let miner = Wallet::from_passphrase("miner");
+
+        let mut ledger = Ledger::with_balances([(alice.address.clone(), 100)]);
+
+        let mut block = Block::genesis();
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 50, 1, 0));
+        block.add_transaction(&Transaction::new(&alice, &bob.address, 50, 1, 1));
+        block.set_coinbase(&miner.address, &params()).unwrap();
+
+        assert_eq!(
+            ledger.apply_block(&block),
+            Err(LedgerError::Overdraft { position: 2, address: alice.address.clone(), balance: 49, required: 51 })
+        );
+
+        // nothing from the rejected block is kept.
+        assert_eq!(ledger.balance(&alice.address), 100);
+        assert_eq!(ledger.balance(&bob.address), 0);
+        assert_eq!(ledger.balance(&miner.address), 0);
+    }
+
+    #[test]
+    fn test_revert_block() {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+        let miner = Wallet::from_passphrase("miner");
+
+        let mut ledger = Ledger::with_balances([(alice.address.clone(), 100)]);
+
+        let mut first = Block::genesis();
+        first.add_transaction(&Transaction::new(&alice, &bob.address, 60, 2, 0));
+        first.set_coinbase(&miner.address, &params()).unwrap();
+
+        let mut second = first.next();
+        second.add_transaction(&Transaction::new(&bob, &alice.address, 30, 5, 0));
+        second.set_coinbase(&miner.address, &params()).unwrap();
+
+        ledger.apply_block(&first).unwrap();
+        ledger.apply_block(&second).unwrap();
+
+        ledger.revert_block(&second).unwrap();
+        assert_eq!(ledger.balance(&alice.address), 38);
+        assert_eq!(ledger.balance(&bob.address), 60);
+        assert_eq!(ledger.balance(&miner.address), 12);
+
+        ledger.revert_block(&first).unwrap();
+        assert_eq!(ledger.balance(&alice.address), 100);
+        assert_eq!(ledger.balance(&bob.address), 0);
+        assert_eq!(ledger.balance(&miner.address), 0);
+        assert_eq!(ledger.nonce(&alice.address), 0);
+
+        assert_eq!(ledger.revert_block(&first), Err(LedgerError::NotApplied));
+        assert_eq!(ledger.balance(&alice.address), 100);
+    }
+}
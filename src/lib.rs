@@ -0,0 +1,18 @@
+
+pub mod bench;
+pub mod chain;
+pub mod ledger;
+pub mod mempool;
+pub mod params;
+pub mod pool;
+pub mod pow;
+pub mod transaction;
+pub mod wallet;
+pub mod crypto;
+pub mod block;
+pub mod parallel_miner;
+pub mod util;
+pub mod miner;
+pub mod protocol;
+#[cfg(test)]
+mod test_util;
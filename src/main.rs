@@ -1,67 +1,187 @@
-#[allow(dead_code)]
 use std::env::args;
 use std::{io, thread};
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use crate::protocol::Message;
-use crate::protocol::peer::Peer;
-
-mod transaction;
-mod wallet;
-mod crypto;
-mod block;
-mod parallel_miner;
-mod util;
-mod miner;
-mod protocol;
-
-const ADDR: &'static str = "127.0.0.1:1111";
-
-fn get_input() -> String {
-    print!("> ");
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
-    input.trim().to_string()
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc};
+use crypton_node::bench;
+use crypton_node::chain::Chain;
+use crypton_node::mempool::TemplateLimits;
+use crypton_node::parallel_miner::CancelToken;
+use crypton_node::params::Params;
+use crypton_node::pool;
+use crypton_node::pool::Pool;
+use crypton_node::protocol::Message;
+use crypton_node::protocol::message::Inventory;
+use crypton_node::protocol::peer::Peer;
+use crypton_node::util::target::Target;
+use crypton_node::wallet;
+
+/// where the node listens unless `-listen` says otherwise.
+const ADDR: &str = "127.0.0.1:1111";
+/// where the pool listens, and the workers connect to, unless `-pool` says otherwise.
+const POOL_ADDR: &str = "127.0.0.1:1112";
+/// where the chain is kept unless `-data` says otherwise.
+const DATA_DIR: &str = "data";
+/// the target of a pool share, as leading zero bits.
+const SHARE_BITS: u32 = 12;
+
+/// sends every line typed into the node to the nodes it is connected to.
+/// `exit` stops the node, without a terminal it keeps running.
+fn chat(peer: &Peer) {
+    for line in io::stdin().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+
+        let line = line.trim();
+        if line.contains("exit") {
+            std::process::exit(0);
+        }
+
+        peer.broadcast(&Message::Text(line.to_string()));
+    }
+}
+
+fn bench(args: impl Iterator<Item = String>) {
+    let config = match bench::Config::from_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    // one json object per line, so the results can be piped into other tools as they come in.
+    bench::run(&config, |report| println!("{}", serde_json::to_string(&report).unwrap()));
 }
 
-fn client() {
-    let r_stream = TcpStream::connect(ADDR).unwrap();
-    let mut w_stream = r_stream.try_clone().unwrap();
-    let mut reader = io::BufReader::new(r_stream);
+/// runs a mining pool on top of the peer's chain and mempool, paying the block rewards to `payout`.
+/// the blocks it mines are announced to the nodes the peer is connected to.
+fn serve_pool(peer: &Peer, payout: String, listen: &str) -> thread::JoinHandle<()> {
+    let peer = peer.clone();
+    let chain = peer.chain();
+    let mempool = peer.mempool();
+    let share_bits = Target::from_leading_zero_bits(SHARE_BITS).to_compact();
+    let params = chain.lock().unwrap().params().clone();
+    let pool = Arc::new(Pool::new(params.clone(), share_bits));
+
+    // the blocks the workers solve, and the ones the peer gets from other nodes.
+    let (sender, blocks) = mpsc::channel();
+    peer.watch_tip(sender.clone());
 
-    // listener thread
-    thread::spawn(move || {
-        loop {
-            let msg: Message = protocol::recv(&mut reader).unwrap();
-            println!("message received: {msg:?}");
+    let addr = pool.listen(listen, sender).expect("could not start the pool");
+    println!("pool listening on {addr}");
+
+    thread::spawn(move || loop {
+        let block = {
+            let chain = chain.lock().unwrap();
+            mempool
+                .lock()
+                .unwrap()
+                .block_template(chain.next_block(), chain.ledger(), &payout, &params, TemplateLimits::default())
+        };
+        pool.set_template(block);
+
+        // the template stays until the tip moves, one way or another.
+        let Ok(block) = blocks.recv() else {
+            return;
+        };
+
+        let mut tip = chain.lock().unwrap();
+
+        // another node's block, the template is stale.
+        if tip.contains(&block.get_hash()) {
+            continue;
         }
-    });
 
-    loop {
-        let input = get_input();
-        if input.contains("exit") {
-            break;
+        // rejected or not, the template is built again on whatever the tip is now.
+        match tip.append(&block) {
+            Ok(_) => {
+                mempool.lock().unwrap().connect_block(&block, tip.ledger());
+                drop(tip);
+
+                peer.broadcast(&Message::Inventory(vec![Inventory::Block(block.get_hash())]));
+                println!("block {} mined, shares: {:?}", block.header.index, pool.shares());
+            }
+            Err(e) => eprintln!("pool block rejected: {e}"),
         }
+    })
+}
 
-        let msg = Message::Text(input);
-        protocol::send(&mut w_stream, &msg).unwrap();
-    }
+/// mines for the pool at `pool`, with the shares credited to `worker`.
+fn worker(worker: &str, pool: &str) {
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    pool::run_worker(pool, worker, threads, &CancelToken::new())
+        .expect("lost the connection to the pool");
 }
 
-fn main() {
-    let mut args = args();
-    let is_server = args.any(|arg| arg == "-server");
+fn resolve(addr: &str) -> Option<SocketAddr> {
+    addr.to_socket_addrs().ok()?.next()
+}
 
-    if is_server {
-        let peer = Peer::new();
-        let (l, b) = peer.run(ADDR).unwrap();
+/// the value of a `-key=value` argument.
+fn arg_value(key: &str) -> Option<String> {
+    args().find_map(|arg| {
+        arg.strip_prefix(key)
+            .and_then(|rest| rest.strip_prefix('='))
+            .map(String::from)
+    })
+}
+
+fn main() {
+    if args().nth(1).as_deref() == Some("-bench") {
+        bench(args().skip(2));
+        return;
+    }
 
-        l.join().unwrap();
-        b.join().unwrap();
+    let pool = arg_value("-pool").unwrap_or(POOL_ADDR.to_string());
 
+    if let Some(name) = arg_value("-worker") {
+        worker(&name, &pool);
         return;
     }
 
-    client();
-}
\ No newline at end of file
+    let data_dir = arg_value("-data").unwrap_or(DATA_DIR.to_string());
+    let chain = Chain::open(&data_dir, Params::default()).expect("could not load the chain");
+    println!("loaded {} blocks from {}", chain.len(), data_dir);
+
+    if let Some(offset) = chain.truncated_at() {
+        eprintln!("dropped an incomplete block record at offset {offset}");
+    }
+
+    let peer = Peer::new(chain);
+
+    for seed in arg_value("-seed").iter().flat_map(|seeds| seeds.split(',')) {
+        match resolve(seed) {
+            Some(addr) => peer.add_address(addr),
+            None => eprintln!("invalid seed address: {seed}"),
+        }
+    }
+
+    if let Some(addr) = arg_value("-sync") {
+        match peer.sync(&addr) {
+            Ok(added) => println!("synced {added} blocks from {addr}"),
+            Err(e) => eprintln!("could not sync from {addr}: {e}"),
+        }
+    }
+
+    if let Some(payout) = arg_value("-payout") {
+        if !wallet::is_valid_address(&payout) {
+            eprintln!("invalid payout address: {payout}");
+            std::process::exit(1);
+        }
+
+        serve_pool(&peer, payout, &pool);
+    }
+
+    let listen = arg_value("-listen").unwrap_or(ADDR.to_string());
+    let (addr, l, b) = peer.run(listen).expect("could not start the node");
+    println!("node {} listening on {addr}", peer.id);
+
+    chat(&peer);
+
+    l.join().unwrap();
+    b.join().unwrap();
+}
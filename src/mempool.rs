@@ -0,0 +1,550 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::block::Block;
+use crate::ledger::Ledger;
+use crate::params::Params;
+use crate::transaction::{Transaction, TransactionError};
+
+/// how many transactions are kept, the lowest fee rate is dropped to make room.
+pub const MAX_TRANSACTIONS: usize = 50_000;
+/// how far ahead of the ledger a sender's nonce may be, which also caps the transactions per sender.
+pub const MAX_NONCE_GAP: u64 = 25;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MempoolError {
+    Duplicate,
+    Coinbase,
+    Invalid(TransactionError),
+    StaleNonce { expected: u64, found: u64 },
+    NonceTooFar { expected: u64, found: u64 },
+    /// `balance` is what is left after the sender's other pending transactions.
+    InsufficientBalance { balance: u64, required: u64 },
+    /// another transaction with the same sender and nonce pays at least as much.
+    Conflict,
+    /// the mempool is full of transactions that pay a higher fee rate.
+    Full,
+}
+
+impl Display for MempoolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolError::Duplicate => write!(f, "transaction is already in the mempool"),
+            MempoolError::Coinbase => write!(f, "coinbase transactions are only valid in blocks"),
+            MempoolError::Invalid(e) => write!(f, "{e}"),
+            MempoolError::StaleNonce { expected, found } => write!(f, "nonce {found} was already used, expected at least {expected}"),
+            MempoolError::NonceTooFar { expected, found } =>
+                write!(f, "nonce {found} is too far ahead of {expected}, at most {MAX_NONCE_GAP} may be pending"),
+            MempoolError::InsufficientBalance { balance, required } => write!(f, "balance {balance} can not pay {required}"),
+            MempoolError::Conflict => write!(f, "a transaction with the same nonce pays a higher fee"),
+            MempoolError::Full => write!(f, "mempool is full of transactions paying a higher fee rate"),
+        }
+    }
+}
+
+impl Error for MempoolError {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateLimits {
+    /// transactions in the template, not counting the coinbase.
+    pub max_count: usize,
+    /// serialized size of those transactions.
+    pub max_bytes: usize,
+}
+
+impl Default for TemplateLimits {
+    fn default() -> Self {
+        Self {
+            max_count: 4096,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    txn: Transaction,
+    size: usize,
+}
+
+impl Entry {
+    fn new(txn: Transaction) -> Self {
+        let size = rmp_serde::to_vec(&txn)
+            .map(|buffer| buffer.len())
+            .unwrap_or(usize::MAX);
+
+        Self { txn, size }
+    }
+
+    /// whether it pays more per byte than `other`.
+    fn outbids(&self, other: &Entry) -> bool {
+        self.txn.fee as u128 * other.size as u128 > other.txn.fee as u128 * self.size as u128
+    }
+}
+
+/// orders entries by fee per byte, then by fee, so the best paying one comes out of the heap first.
+struct ByFeeRate<'a>(&'a Entry);
+
+impl Ord for ByFeeRate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lhs = self.0.txn.fee as u128 * other.0.size as u128;
+        let rhs = other.0.txn.fee as u128 * self.0.size as u128;
+
+        lhs.cmp(&rhs)
+            .then(self.0.txn.fee.cmp(&other.0.txn.fee))
+            // older first, then any stable order.
+            .then(other.0.txn.timestamp.cmp(&self.0.txn.timestamp))
+            .then(other.0.txn.hash.cmp(&self.0.txn.hash))
+    }
+}
+
+impl PartialOrd for ByFeeRate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ByFeeRate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ByFeeRate<'_> {}
+
+/// the pending transactions that are waiting to be mined.
+///
+/// a sender may have one transaction per nonce, a new one for a nonce
+/// that is already taken only gets in if it pays a higher fee.
+/// once `limit` transactions are pending, a new one has to pay a higher fee rate
+/// than the cheapest one, which is dropped along with the sender's transactions after it.
+#[derive(Debug)]
+pub struct Mempool {
+    entries: HashMap<[u8; 32], Entry>,
+    by_nonce: HashMap<(String, u64), [u8; 32]>,
+    limit: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::with_limit(MAX_TRANSACTIONS)
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_nonce: HashMap::new(),
+            limit: limit.max(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &[u8; 32]) -> Option<&Transaction> {
+        self.entries.get(hash).map(|entry| &entry.txn)
+    }
+
+    /// validates the transaction against the ledger of the current tip and keeps it.
+    ///
+    /// the sender has to be able to pay for it on top of their other pending transactions.
+    pub fn insert(&mut self, txn: Transaction, ledger: &Ledger) -> Result<(), MempoolError> {
+        if self.entries.contains_key(&txn.hash) {
+            return Err(MempoolError::Duplicate);
+        }
+
+        if txn.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+
+        txn.verify().map_err(MempoolError::Invalid)?;
+
+        let account = ledger.account(&txn.sender);
+        if txn.nonce < account.nonce {
+            return Err(MempoolError::StaleNonce { expected: account.nonce, found: txn.nonce });
+        }
+
+        if txn.nonce - account.nonce >= MAX_NONCE_GAP {
+            return Err(MempoolError::NonceTooFar { expected: account.nonce, found: txn.nonce });
+        }
+
+        // a transaction it replaces does not count against the balance.
+        let pending: u64 = (account.nonce..account.nonce.saturating_add(MAX_NONCE_GAP))
+            .filter(|nonce| *nonce != txn.nonce)
+            .filter_map(|nonce| self.by_nonce.get(&(txn.sender.clone(), nonce)))
+            .map(|hash| &self.entries[hash].txn)
+            .map(|pending| pending.amount.saturating_add(pending.fee))
+            .fold(0, u64::saturating_add);
+
+        let balance = account.balance.saturating_sub(pending);
+        let required = txn.amount.saturating_add(txn.fee);
+        if balance < required {
+            return Err(MempoolError::InsufficientBalance { balance, required });
+        }
+
+        let key = (txn.sender.clone(), txn.nonce);
+        let existing = self.by_nonce.get(&key).copied();
+
+        if let Some(existing) = existing {
+            if self.entries[&existing].txn.fee >= txn.fee {
+                return Err(MempoolError::Conflict);
+            }
+        }
+
+        let entry = Entry::new(txn);
+
+        if existing.is_none() && self.entries.len() >= self.limit {
+            self.make_room(&entry)?;
+        }
+
+        if let Some(existing) = existing {
+            self.remove(&existing);
+        }
+
+        self.by_nonce.insert(key, entry.txn.hash);
+        self.entries.insert(entry.txn.hash, entry);
+
+        Ok(())
+    }
+
+    /// drops the transaction with the lowest fee rate, if `entry` pays a higher one,
+    /// and the sender's transactions after it, which can not be mined without it.
+    fn make_room(&mut self, entry: &Entry) -> Result<(), MempoolError> {
+        let lowest = self.entries
+            .values()
+            .min_by(|lhs, rhs| ByFeeRate(lhs).cmp(&ByFeeRate(rhs)))
+            .ok_or(MempoolError::Full)?;
+
+        let depends_on_it = lowest.txn.sender == entry.txn.sender && lowest.txn.nonce < entry.txn.nonce;
+        if !entry.outbids(lowest) || depends_on_it {
+            return Err(MempoolError::Full);
+        }
+
+        let sender = lowest.txn.sender.clone();
+        let nonce = lowest.txn.nonce;
+
+        for nonce in nonce..=nonce.saturating_add(MAX_NONCE_GAP) {
+            match self.by_nonce.get(&(sender.clone(), nonce)).copied() {
+                Some(hash) => self.remove(&hash),
+                None => break,
+            };
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, hash: &[u8; 32]) -> Option<Transaction> {
+        let entry = self.entries.remove(hash)?;
+        self.by_nonce.remove(&(entry.txn.sender.clone(), entry.txn.nonce));
+        Some(entry.txn)
+    }
+
+    /// drops what the newly connected block included, or made impossible to include.
+    /// `ledger` is the ledger after the block was applied.
+    pub fn connect_block(&mut self, block: &Block, ledger: &Ledger) {
+        for txn in block.transactions.iter() {
+            self.remove(&txn.hash);
+
+            // a different transaction for the same nonce can never be mined now.
+            if let Some(conflict) = self.by_nonce.get(&(txn.sender.clone(), txn.nonce)).copied() {
+                self.remove(&conflict);
+            }
+        }
+
+        let stale: Vec<[u8; 32]> = self.entries
+            .values()
+            .filter(|entry| entry.txn.nonce < ledger.nonce(&entry.txn.sender))
+            .map(|entry| entry.txn.hash)
+            .collect();
+
+        for hash in stale {
+            self.remove(&hash);
+        }
+    }
+
+    /// fills an empty block from `Chain::next_block` with the best paying transactions,
+    /// and a coinbase paying `miner`. the block still has to be mined.
+    ///
+    /// transactions of one sender are taken in nonce order, starting at the nonce the ledger expects,
+    /// and only while the sender can still pay for them. a transaction whose fee would overflow
+    /// the coinbase is left out.
+    pub fn block_template(
+        &self,
+        mut block: Block,
+        ledger: &Ledger,
+        miner: &str,
+        params: &Params,
+        limits: TemplateLimits,
+    ) -> Block {
+        // the heap only holds the next transaction of every sender.
+        let mut heap = BinaryHeap::new();
+        let mut spent: HashMap<&str, u64> = HashMap::new();
+
+        for entry in self.entries.values() {
+            if entry.txn.nonce == ledger.nonce(&entry.txn.sender) {
+                heap.push(ByFeeRate(entry));
+            }
+        }
+
+        let mut bytes = 0usize;
+        let mut reward = params.block_subsidy(block.header.index);
+
+        while let Some(ByFeeRate(entry)) = heap.pop() {
+            if block.transactions.len() >= limits.max_count {
+                break;
+            }
+
+            let txn = &entry.txn;
+
+            if bytes.saturating_add(entry.size) > limits.max_bytes {
+                continue;
+            }
+
+            let spent = spent.entry(&txn.sender).or_default();
+            let required = txn.amount.saturating_add(txn.fee);
+            let available = ledger.balance(&txn.sender).saturating_sub(*spent);
+
+            // the rest of this sender's transactions wait for a later block.
+            if available < required {
+                continue;
+            }
+
+            let Some(total) = reward.checked_add(txn.fee) else {
+                continue;
+            };
+
+            reward = total;
+            *spent += required;
+            bytes += entry.size;
+            block.add_transaction(txn);
+
+            let next = txn.nonce
+                .checked_add(1)
+                .and_then(|nonce| self.by_nonce.get(&(txn.sender.clone(), nonce)));
+
+            if let Some(next) = next {
+                heap.push(ByFeeRate(&self.entries[next]));
+            }
+        }
+
+        block
+            .set_coinbase(miner, params)
+            .expect("the coinbase reward was checked as the transactions were added");
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wallet::Wallet;
+    use super::*;
+
+    struct Fixture {
+        alice: Wallet,
+        bob: Wallet,
+        miner: Wallet,
+        ledger: Ledger,
+        params: Params,
+    }
+
+    fn fixture() -> Fixture {
+        let alice = Wallet::from_passphrase("alice");
+        let bob = Wallet::from_passphrase("bob");
+        let miner = Wallet::from_passphrase("miner");
+
+        let ledger = Ledger::with_balances([
+            (alice.address.clone(), 100),
+            (bob.address.clone(), 100),
+        ]);
+
+        let params = Params::default();
+
+        Fixture { alice, bob, miner, ledger, params }
+    }
+
+    #[test]
+    fn test_insert() {
+        let f = fixture();
+        let mut mempool = Mempool::new();
+
+        let txn = Transaction::new(&f.alice, &f.bob.address, 10, 1, 0);
+
+        let mut tampered = txn.clone();
+        tampered.amount = 20;
+        assert!(matches!(mempool.insert(tampered, &f.ledger), Err(MempoolError::Invalid(_))));
+
+        mempool.insert(txn.clone(), &f.ledger).unwrap();
+        assert_eq!(mempool.insert(txn.clone(), &f.ledger), Err(MempoolError::Duplicate));
+
+        let coinbase = Transaction::coinbase(&f.alice.address, 10, 0);
+        assert_eq!(mempool.insert(coinbase, &f.ledger), Err(MempoolError::Coinbase));
+
+        let broke = Transaction::new(&f.miner, &f.bob.address, 10, 1, 0);
+        assert_eq!(
+            mempool.insert(broke, &f.ledger),
+            Err(MempoolError::InsufficientBalance { balance: 0, required: 11 })
+        );
+
+        // same nonce, a fee that is not higher does not replace it.
+        let cheaper = Transaction::new(&f.alice, &f.bob.address, 5, 1, 0);
+        assert_eq!(mempool.insert(cheaper, &f.ledger), Err(MempoolError::Conflict));
+
+        let better = Transaction::new(&f.alice, &f.bob.address, 5, 2, 0);
+        mempool.insert(better.clone(), &f.ledger).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&better.hash));
+        assert!(!mempool.contains(&txn.hash));
+    }
+
+    #[test]
+    fn test_insert_pending_balance() {
+        let f = fixture();
+        let mut mempool = Mempool::new();
+
+        // alice has 100, what she has pending counts against it.
+        mempool.insert(Transaction::new(&f.alice, &f.bob.address, 60, 1, 0), &f.ledger).unwrap();
+        assert_eq!(
+            mempool.insert(Transaction::new(&f.alice, &f.bob.address, 60, 1, 1), &f.ledger),
+            Err(MempoolError::InsufficientBalance { balance: 39, required: 61 })
+        );
+
+        // a replacement only has to fit next to the others.
+        mempool.insert(Transaction::new(&f.alice, &f.bob.address, 38, 1, 1), &f.ledger).unwrap();
+        mempool.insert(Transaction::new(&f.alice, &f.bob.address, 90, 2, 0), &f.ledger).unwrap_err();
+        mempool.insert(Transaction::new(&f.alice, &f.bob.address, 59, 2, 0), &f.ledger).unwrap();
+        assert_eq!(mempool.len(), 2);
+
+        assert_eq!(
+            mempool.insert(Transaction::new(&f.bob, &f.alice.address, 1, 1, MAX_NONCE_GAP), &f.ledger),
+            Err(MempoolError::NonceTooFar { expected: 0, found: MAX_NONCE_GAP })
+        );
+    }
+
+    #[test]
+    fn test_insert_full() {
+        let f = fixture();
+        let mut mempool = Mempool::with_limit(2);
+
+        let first = Transaction::new(&f.alice, &f.bob.address, 10, 2, 0);
+        let second = Transaction::new(&f.alice, &f.bob.address, 10, 4, 1);
+        let other = Transaction::new(&f.bob, &f.alice.address, 10, 4, 0);
+        mempool.insert(first.clone(), &f.ledger).unwrap();
+        mempool.insert(second.clone(), &f.ledger).unwrap();
+
+        // the cheapest would have to go, it pays more than this one.
+        let cheap = Transaction::new(&f.bob, &f.alice.address, 10, 1, 0);
+        assert_eq!(mempool.insert(cheap, &f.ledger), Err(MempoolError::Full));
+
+        // alice's first goes, and her second with it, it can not be mined on its own.
+        mempool.insert(other.clone(), &f.ledger).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&other.hash));
+        assert!(!mempool.contains(&second.hash));
+
+        // replacing a transaction does not need room.
+        mempool.insert(first, &f.ledger).unwrap();
+        let better = Transaction::new(&f.bob, &f.alice.address, 10, 6, 0);
+        mempool.insert(better.clone(), &f.ledger).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert!(mempool.contains(&better.hash));
+    }
+
+    #[test]
+    fn test_template_orders_by_fee() {
+        let f = fixture();
+        let mut mempool = Mempool::new();
+
+        let cheap = Transaction::new(&f.alice, &f.miner.address, 10, 1, 0);
+        let rich = Transaction::new(&f.bob, &f.miner.address, 10, 5, 0);
+        // pays the most but has to wait for alice's first transaction.
+        let follow_up = Transaction::new(&f.alice, &f.miner.address, 10, 9, 1);
+
+        for txn in [&cheap, &rich, &follow_up] {
+            mempool.insert(txn.clone(), &f.ledger).unwrap();
+        }
+
+        let template = mempool.block_template(Block::genesis().next(), &f.ledger, &f.miner.address, &f.params, TemplateLimits::default());
+
+        let hashes: Vec<[u8; 32]> = template.transactions[1..].iter().map(|txn| txn.hash).collect();
+        assert_eq!(hashes, vec![rich.hash, cheap.hash, follow_up.hash]);
+        assert_eq!(template.transactions[0].amount, f.params.block_subsidy(1) + 15);
+        assert_eq!(template.validate(&f.params), Ok(()));
+
+        let mut ledger = f.ledger.clone();
+        ledger.apply_block(&template).unwrap();
+
+        let limits = TemplateLimits { max_count: 2, ..TemplateLimits::default() };
+        let template = mempool.block_template(Block::genesis().next(), &f.ledger, &f.miner.address, &f.params, limits);
+        assert_eq!(template.transactions.len(), 3);
+        assert_eq!(template.transactions[1].hash, rich.hash);
+    }
+
+    #[test]
+    fn test_template_skips_overdraft() {
+        let f = fixture();
+        let mut mempool = Mempool::new();
+
+        // alice could pay for both when they came in, she spent some since.
+        let before = Ledger::with_balances([(f.alice.address.clone(), 200)]);
+        let first = Transaction::new(&f.alice, &f.bob.address, 60, 1, 0);
+        let second = Transaction::new(&f.alice, &f.bob.address, 60, 1, 1);
+        mempool.insert(first.clone(), &before).unwrap();
+        mempool.insert(second, &before).unwrap();
+
+        let template = mempool.block_template(Block::genesis().next(), &f.ledger, &f.miner.address, &f.params, TemplateLimits::default());
+        assert_eq!(template.transactions.len(), 2);
+        assert_eq!(template.transactions[1].hash, first.hash);
+    }
+
+    #[test]
+    fn test_connect_block() {
+        let f = fixture();
+        let mut mempool = Mempool::new();
+
+        let included = Transaction::new(&f.alice, &f.bob.address, 10, 1, 0);
+        let next = Transaction::new(&f.alice, &f.bob.address, 10, 1, 1);
+        let unrelated = Transaction::new(&f.bob, &f.alice.address, 10, 1, 0);
+
+        for txn in [&included, &next, &unrelated] {
+            mempool.insert(txn.clone(), &f.ledger).unwrap();
+        }
+
+        // a competing block mined alice's nonce 0 with a different transaction.
+        let mut block = Block::genesis();
+        block.add_transaction(&Transaction::new(&f.alice, &f.miner.address, 20, 3, 0));
+        block.set_coinbase(&f.miner.address, &f.params).unwrap();
+
+        let mut ledger = f.ledger.clone();
+        ledger.apply_block(&block).unwrap();
+        mempool.connect_block(&block, &ledger);
+
+        assert!(!mempool.contains(&included.hash));
+        assert!(mempool.contains(&next.hash));
+        assert!(mempool.contains(&unrelated.hash));
+
+        let mut block = block.next();
+        block.add_transaction(&next);
+        block.set_coinbase(&f.miner.address, &f.params).unwrap();
+        ledger.apply_block(&block).unwrap();
+        mempool.connect_block(&block, &ledger);
+
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&unrelated.hash));
+    }
+}
@@ -1,100 +1,159 @@
+use std::ops::Range;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use crate::block::Block;
-use crate::crypto::sha256;
-use crate::util::hash_ring::HashRing;
+use std::time::{Duration, UNIX_EPOCH};
+use crate::block::{Block, BlockHeader};
+use crate::parallel_miner;
+use crate::parallel_miner::{CancelToken, Progress};
+use crate::pow::Pow;
+use crate::util::target::Target;
 
-fn mine_single_core(block: &mut Block) {
-    let mut buffer = block.get_payload();
-
-    loop {
-        // hash
-        buffer[88..96].copy_from_slice(&block.nonce.to_be_bytes());
-        let hash = sha256::hash(buffer);
+#[derive(Debug)]
+pub enum Outcome {
+    Solved(BlockHeader),
+    /// the job was cancelled, usually because a new block made its template stale.
+    Cancelled,
+    TimedOut,
+}
 
-        let is_valid = hash.iter()
-            .take(block.difficulty as usize)
-            .all(|b| *b == 0u8);
+/// a block header being mined in the background, the transactions are only needed for its merkle root.
+///
+/// dropping the job cancels it, so replacing a job with one for a newer template
+/// stops the old threads without waiting for them.
+pub struct MiningJob {
+    pow: Pow,
+    threads: usize,
+    /// the target from `start_with_target`, none to mine for the header's own.
+    target: Option<Target>,
+    nonces: Range<u64>,
+    cancel: CancelToken,
+    progress: Arc<Progress>,
+    result: mpsc::Receiver<Option<BlockHeader>>,
+}
 
-        if is_valid {
-            break;
-        }
+impl MiningJob {
+    pub fn start(header: BlockHeader, pow: Pow, threads: usize) -> Self {
+        Self::spawn(header, pow, threads, None, 0..u64::MAX)
+    }
 
-        // increment
-        block.nonce += 1;
+    /// like `start`, but looks for a hash meeting `target` instead of the block's own, among `nonces`.
+    /// pool workers use it to find shares, which take less work than the block.
+    pub fn start_with_target(header: BlockHeader, pow: Pow, threads: usize, target: Target, nonces: Range<u64>) -> Self {
+        Self::spawn(header, pow, threads, Some(target), nonces)
     }
-}
 
-fn miner_thread(idx: usize, difficulty: &u64, mut payload: [u8; 96], hash_ring: &HashRing, valid_nonce: Arc<AtomicU64>) {
-    // fetch
-    for nonce in (0..)
-        // only try nonces which belong to this thread.
-        .filter(|n| hash_ring.get(*n) == idx) {
-
-        // check to stop
-        // if the valid_nonce is already found, stop execution
-        if valid_nonce.load(Ordering::SeqCst) != 0
-        {
-            return;
-        }
+    fn spawn(mut header: BlockHeader, pow: Pow, threads: usize, job_target: Option<Target>, nonces: Range<u64>) -> Self {
+        let target = job_target.unwrap_or(Target::from_compact(header.bits));
 
-        // set up
-        payload[88..96].copy_from_slice(&nonce.to_be_bytes());
-        let hash = sha256::hash(&payload);
+        // the clock only moves forward, a block that is already ahead of it keeps its time.
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        header.timestamp = header.timestamp.max(now);
 
-        // check
-        let is_valid = hash.iter()
-            .take(*difficulty as usize)
-            .all(|b| *b == 0u8);
+        let cancel = CancelToken::new();
+        let progress = Arc::new(Progress::new());
+        let (sender, result) = mpsc::channel();
 
-        if is_valid {
-            // set
-            valid_nonce.store(nonce as u64, Ordering::SeqCst);
-            return;
+        let job_cancel = cancel.clone();
+        let job_progress = Arc::clone(&progress);
+        let job_nonces = nonces.clone();
+        thread::spawn(move || {
+            let solved = loop {
+                let payload = header.get_payload();
+
+                if let Some(nonce) = parallel_miner::search(payload, pow.algorithm(), target, job_nonces.clone(), threads, &job_cancel, &job_progress) {
+                    header.nonce = nonce;
+                    break Some(header);
+                }
+
+                if job_cancel.is_cancelled() {
+                    break None;
+                }
+
+                // every nonce was tried, the timestamp is the only other field the miner may change.
+                let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+                header.timestamp = (header.timestamp + 1).max(now);
+            };
+
+            // nobody is waiting any more if the job was dropped.
+            let _ = sender.send(solved);
+        });
+
+        Self {
+            pow,
+            threads,
+            target: job_target,
+            nonces,
+            cancel,
+            progress,
+            result,
         }
     }
-}
 
-fn mine_multi_core(block: &mut Block, parallelism_available: usize) {
-    let valid_nonce: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
-    let hash_ring = HashRing::new(parallelism_available);
+    /// throws the current work away and starts mining `header` instead.
+    ///
+    /// the old threads are cancelled and new ones started, with the same thread count,
+    /// target and nonce range. the progress starts over, so `progress` has to be asked for again.
+    pub fn restart(&mut self, header: BlockHeader) {
+        *self = Self::spawn(header, self.pow, self.threads, self.target, self.nonces.clone());
+    }
 
-    let mut threads = Vec::with_capacity(parallelism_available);
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
 
-    for idx in 0..parallelism_available {
-        let hash_ring = hash_ring.clone();
-        let payload = block.get_payload();
-        let difficulty = block.difficulty;
-        let nonce = Arc::clone(&valid_nonce);
+    pub fn progress(&self) -> Arc<Progress> {
+        Arc::clone(&self.progress)
+    }
 
-        threads.push(thread::spawn(move || {
-            miner_thread(
-                idx,
-                &difficulty,
-                payload.clone(),
-                &hash_ring,
-                Arc::clone(&nonce),
-            );
-        }));
+    /// waits up to `timeout` for the job to finish, none if it is still running.
+    pub fn poll(&self, timeout: Duration) -> Option<Outcome> {
+        match self.result.recv_timeout(timeout) {
+            Ok(Some(header)) => Some(Outcome::Solved(header)),
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => Some(Outcome::Cancelled),
+            Err(RecvTimeoutError::Timeout) => None,
+        }
     }
 
-    for thread in threads {
-        thread.join().unwrap();
+    /// waits for the job to finish, cancelling it once `timeout` has passed.
+    pub fn wait(self, timeout: Option<Duration>) -> Outcome {
+        let result = match timeout {
+            Some(timeout) => self.result.recv_timeout(timeout),
+            None => self.result.recv().map_err(RecvTimeoutError::from),
+        };
+
+        match result {
+            Ok(Some(header)) => Outcome::Solved(header),
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => Outcome::Cancelled,
+            Err(RecvTimeoutError::Timeout) => Outcome::TimedOut,
+        }
     }
+}
 
-    block.nonce = valid_nonce.load(Ordering::Relaxed);
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.cancel();
+    }
 }
 
-pub fn mine(block: &mut Block) {
+/// mines the block on every core, blocking until it is solved.
+pub fn mine(block: &mut Block, pow: Pow) {
     let parallelism_available = thread::available_parallelism()
         .map(|s| s.get())
         .unwrap_or(1);
 
-    if block.difficulty >= 2 && parallelism_available > 1 {
-        mine_multi_core(block, parallelism_available);
+    // easy targets are found before the threads are even started.
+    let threads = if Target::from_compact(block.header.bits).leading_zero_bits() >= 16 {
+        parallelism_available
     } else {
-        mine_single_core(block);
+        1
+    };
+
+    match MiningJob::start(block.header, pow, threads).wait(None) {
+        Outcome::Solved(header) => block.header = header,
+        // without a cancel the search only stops early once all 2^64 nonces are used up.
+        outcome => panic!("mining stopped without a solution: {outcome:?}"),
     }
 }
 
@@ -111,9 +170,11 @@ mod tests {
 
     fn add_transactions(block: &mut Block) {
         for i in 0..1024 {
-            let txn = Transaction::new(&ALICE, &BOB, i, 1);
+            let txn = Transaction::new(&ALICE, &BOB.address, i, 1, i);
             block.add_transaction(&txn);
         }
+
+        block.update_mrkl_root();
     }
 
     #[test]
@@ -121,10 +182,10 @@ mod tests {
         for d in 1..=3 {
             println!("difficulty: {}", d);
             let mut block = Block::genesis();
-            block.difficulty = d;
+            block.header.bits = Target::from_leading_zero_bits(8 * d).to_compact();
 
             let start = Instant::now();
-            mine(&mut block);
+            mine(&mut block, Pow::Sha256);
             println!("block: {:#?}", block);
             println!("elapsed: {:?}\n", start.elapsed());
         }
@@ -133,15 +194,16 @@ mod tests {
     #[test]
     fn test_custom_block() {
         let mut block = Block::genesis();
-        block.difficulty = 2;
+        block.header.bits = Target::from_leading_zero_bits(16).to_compact();
 
         let alice = Wallet::from_passphrase("alice");
         let bob = Wallet::from_passphrase("bob");
 
         for i in 0..1024 {
-            let txn = Transaction::new(&alice, &bob, i, 1);
+            let txn = Transaction::new(&alice, &bob.address, i, 1, i);
             block.add_transaction(&txn);
         }
+        block.update_mrkl_root();
 
         println!("{:#?}", block);
     }
@@ -149,11 +211,11 @@ mod tests {
     #[test]
     fn test_block_chain() {
         let mut block = Block::genesis();
-        block.difficulty = 2;
+        block.header.bits = Target::from_leading_zero_bits(16).to_compact();
 
         for _ in 0..10 {
             add_transactions(&mut block);
-            mine(&mut block);
+            mine(&mut block, Pow::Sha256);
 
             let start = Instant::now();
             println!("{:#?}", block);
@@ -162,4 +224,78 @@ mod tests {
             block = block.next();
         }
     }
+
+    #[test]
+    fn test_cancel_job() {
+        let mut block = Block::genesis();
+        block.header.bits = Target::from_leading_zero_bits(64).to_compact();
+
+        let job = MiningJob::start(block.header, Pow::Sha256, 1);
+        thread::sleep(Duration::from_millis(50));
+        job.cancel();
+        assert!(matches!(job.wait(None), Outcome::Cancelled));
+
+        let job = MiningJob::start(block.header, Pow::Sha256, 1);
+        assert!(matches!(job.wait(Some(Duration::from_millis(50))), Outcome::TimedOut));
+    }
+
+    #[test]
+    fn test_roll_timestamp() {
+        let mut block = Block::genesis();
+        block.header.bits = Target::from_leading_zero_bits(12).to_compact();
+        // ahead of the clock, so the job rolls it the same way every run.
+        block.header.timestamp = 1 << 40;
+
+        // 16 nonces are not enough for 12 zero bits at this timestamp, the job has to roll it.
+        let target = Target::from_compact(block.header.bits);
+        let job = MiningJob::start_with_target(block.header, Pow::Sha256, 2, target, 0..16);
+        let Outcome::Solved(solved) = job.wait(Some(Duration::from_secs(60))) else {
+            panic!("job was not solved");
+        };
+
+        assert!(solved.nonce < 16);
+        assert!(solved.timestamp > block.header.timestamp);
+        assert!(Target::from_compact(solved.bits).is_met_by(&solved.get_hash()));
+    }
+
+    #[test]
+    fn test_restart_job() {
+        let mut stale = Block::genesis();
+        stale.header.bits = Target::from_leading_zero_bits(64).to_compact();
+
+        let mut job = MiningJob::start(stale.header, Pow::Sha256, 1);
+        while job.progress().hashes() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(job.progress().hashrate() > 0.0);
+
+        // a new block came in, the template is now one that is easy to solve.
+        let mut block = Block::genesis();
+        block.header.bits = Target::from_leading_zero_bits(8).to_compact();
+        job.restart(block.header);
+
+        let Outcome::Solved(header) = job.wait(Some(Duration::from_secs(10))) else {
+            panic!("restarted job was not solved");
+        };
+        assert!(Target::from_compact(header.bits).is_met_by(&header.get_hash()));
+    }
+
+    #[test]
+    fn test_restart_keeps_target() {
+        // a block nobody can mine, but a share of it is easy.
+        let mut block = Block::genesis();
+        block.header.bits = Target::from_leading_zero_bits(64).to_compact();
+        let share = Target::from_leading_zero_bits(4);
+
+        let mut job = MiningJob::start_with_target(block.header, Pow::Sha256, 1, share, 1000..u64::MAX);
+        block.header.index = 1;
+        job.restart(block.header);
+
+        let Outcome::Solved(header) = job.wait(Some(Duration::from_secs(10))) else {
+            panic!("restarted job lost its share target");
+        };
+        assert_eq!(header.index, 1);
+        assert!(header.nonce >= 1000);
+        assert!(share.is_met_by(&header.get_hash()));
+    }
 }
\ No newline at end of file
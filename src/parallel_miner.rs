@@ -0,0 +1,271 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::block::PAYLOAD_SIZE;
+use crate::pow::PowAlgorithm;
+use crate::util::target::Target;
+
+/// how many nonces a thread takes from the shared counter at once,
+/// large enough that the threads rarely touch the counter.
+pub const CHUNK_SIZE: u64 = 1 << 14;
+
+/// stops a running search from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// how far a search got, readable while it is still running.
+#[derive(Debug)]
+pub struct Progress {
+    hashes: AtomicU64,
+    started: Instant,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            hashes: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// how many nonces were hashed, by all threads together.
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// hashes per second since the search started.
+    pub fn hashrate(&self) -> f64 {
+        self.hashes() as f64 / self.elapsed().as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the state shared by the threads of one search.
+struct Shared<'a> {
+    next: AtomicU64,
+    end: u64,
+    found: OnceLock<u64>,
+    progress: &'a Progress,
+}
+
+impl Shared<'_> {
+    fn is_done(&self, cancel: &CancelToken) -> bool {
+        self.found.get().is_some() || cancel.is_cancelled()
+    }
+
+    /// the next range of nonces nobody has tried yet, none once every nonce was handed out.
+    fn take_chunk(&self) -> Option<(u64, u64)> {
+        // saturating, so the counter can not wrap around and hand out the same nonces again.
+        let start = self.next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                (next < self.end).then(|| next.saturating_add(CHUNK_SIZE))
+            })
+            .ok()?;
+
+        Some((start, start.saturating_add(CHUNK_SIZE).min(self.end)))
+    }
+}
+
+fn search_thread(payload: [u8; PAYLOAD_SIZE], pow: &dyn PowAlgorithm, target: Target, shared: &Shared, cancel: &CancelToken) {
+    let mut hash = pow.nonce_hasher(payload);
+    let interval = pow.check_interval().max(1);
+
+    while !shared.is_done(cancel) {
+        let Some((start, end)) = shared.take_chunk() else {
+            return;
+        };
+
+        // a cancel or a solution of another thread is noticed, and the progress added to,
+        // every `check_interval` nonces instead of only once per chunk.
+        for batch in (start..end).step_by(interval as usize) {
+            if shared.is_done(cancel) {
+                return;
+            }
+
+            let batch_end = batch.saturating_add(interval).min(end);
+
+            if let Some(nonce) = (batch..batch_end).find(|nonce| target.is_met_by(&hash(*nonce))) {
+                // another thread may have been faster, either nonce is fine.
+                let _ = shared.found.set(nonce);
+                shared.progress.hashes.fetch_add(nonce - batch + 1, Ordering::Relaxed);
+                return;
+            }
+
+            shared.progress.hashes.fetch_add(batch_end - batch, Ordering::Relaxed);
+        }
+    }
+}
+
+/// searches `nonces` for one that makes the payload's hash meet the target, on `threads` threads.
+/// none if it was cancelled or no nonce in the range meets the target.
+///
+/// the threads take contiguous chunks of nonces from a shared counter,
+/// so a slow thread never holds the others back and no nonce is tried twice.
+pub fn search(
+    payload: [u8; PAYLOAD_SIZE],
+    pow: &dyn PowAlgorithm,
+    target: Target,
+    nonces: Range<u64>,
+    threads: usize,
+    cancel: &CancelToken,
+    progress: &Progress,
+) -> Option<u64> {
+    let shared = Shared {
+        next: AtomicU64::new(nonces.start),
+        end: nonces.end,
+        found: OnceLock::new(),
+        progress,
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| search_thread(payload, pow, target, &shared, cancel));
+        }
+    });
+
+    shared.found.get().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::pow::{Pow, Sha256};
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let mut block = Block::genesis();
+        let target = Target::from_leading_zero_bits(12);
+
+        for threads in [1, 4] {
+            let progress = Progress::new();
+            block.header.nonce = search(block.get_payload(), &Sha256, target, 0..u64::MAX, threads, &CancelToken::new(), &progress).unwrap();
+
+            assert!(target.is_met_by(&block.get_hash()));
+            assert!(progress.hashes() > 0);
+        }
+    }
+
+    #[test]
+    fn test_search_range() {
+        let payload = Block::genesis().get_payload();
+        let none = Target::from_bytes([0u8; 32]);
+
+        // nonce 0 is as good an answer as any other.
+        assert_eq!(search(payload, &Sha256, Target::MAX, 0..u64::MAX, 1, &CancelToken::new(), &Progress::new()), Some(0));
+        assert_eq!(search(payload, &Sha256, Target::MAX, 5..10, 1, &CancelToken::new(), &Progress::new()), Some(5));
+
+        let progress = Progress::new();
+        assert_eq!(search(payload, &Sha256, none, 0..CHUNK_SIZE + 10, 3, &CancelToken::new(), &progress), None);
+        assert_eq!(progress.hashes(), CHUNK_SIZE + 10);
+
+        // the last chunk ends at the end of the range instead of wrapping around.
+        let progress = Progress::new();
+        assert_eq!(search(payload, &Sha256, none, u64::MAX - 10..u64::MAX, 2, &CancelToken::new(), &progress), None);
+        assert_eq!(progress.hashes(), 10);
+    }
+
+    #[test]
+    fn test_search_pow() {
+        let mut block = Block::genesis();
+        let target = Target::from_leading_zero_bits(4);
+
+        for pow in [Pow::DoubleSha256, Pow::Scrypt] {
+            let algorithm = pow.algorithm();
+            block.header.nonce = search(block.get_payload(), algorithm, target, 0..u64::MAX, 2, &CancelToken::new(), &Progress::new()).unwrap();
+
+            assert!(target.is_met_by(&block.get_pow_hash(algorithm)));
+        }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cancel = CancelToken::new();
+        let block = Block::genesis();
+
+        let canceller = cancel.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+
+        // nothing meets an all zero target.
+        let progress = Progress::new();
+        let nonce = search(block.get_payload(), &Sha256, Target::from_bytes([0u8; 32]), 0..u64::MAX, 2, &cancel, &progress);
+        handle.join().unwrap();
+
+        assert_eq!(nonce, None);
+        assert!(progress.hashes() > 0);
+        assert!(progress.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_cancel_scrypt() {
+        let cancel = CancelToken::new();
+        let block = Block::genesis();
+
+        let canceller = cancel.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+            Instant::now()
+        });
+
+        // a scrypt hash is slow enough that a whole chunk takes many seconds.
+        let progress = Progress::new();
+        let nonce = search(block.get_payload(), Pow::Scrypt.algorithm(), Target::from_bytes([0u8; 32]), 0..u64::MAX, 2, &cancel, &progress);
+        let stopped = handle.join().unwrap().elapsed();
+
+        assert_eq!(nonce, None);
+        assert!(progress.hashes() < CHUNK_SIZE);
+        assert!(stopped < Duration::from_secs(2), "took {stopped:?} to stop");
+    }
+
+    /// run with `cargo test --release hashrate -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn test_hashrate() {
+        let block = Block::genesis();
+        let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+        for threads in (0..).map(|i| 1 << i).take_while(|n| *n <= cores) {
+            let cancel = CancelToken::new();
+            let canceller = cancel.clone();
+            let handle = thread::spawn(move || {
+                thread::sleep(Duration::from_secs(2));
+                canceller.cancel();
+            });
+
+            let progress = Progress::new();
+            search(block.get_payload(), &Sha256, Target::from_bytes([0u8; 32]), 0..u64::MAX, threads, &cancel, &progress);
+            handle.join().unwrap();
+
+            println!("{threads} threads: {:.0} H/s", progress.hashrate());
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::pow::Pow;
+use crate::util::target::Target;
+
+/// the smallest units in one coin.
+pub const COIN: u64 = 100_000_000;
+
+/// consensus rules every node on the chain has to agree on from genesis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Params {
+    /// tells networks apart, nodes on another network are dropped when they connect.
+    pub network: u32,
+
+    /// the subsidy paid by the coinbase of the first blocks.
+    pub block_reward: u64,
+    /// the subsidy is halved every this many blocks.
+    pub halving_interval: u64,
+
+    /// the function block payloads are hashed with for the proof of work.
+    pub pow: Pow,
+    /// the easiest target a block may have, in compact form.
+    pub pow_limit: u32,
+    /// the target of the first block, in compact form.
+    pub initial_bits: u32,
+    /// the time between blocks the difficulty is adjusted towards, in seconds.
+    pub target_block_time: u64,
+    /// the difficulty is adjusted every this many blocks.
+    pub retarget_interval: u64,
+    /// how far ahead of the local clock a block timestamp may be, in seconds.
+    pub max_future_drift: u64,
+    /// a block has to be newer than the median timestamp of this many blocks before it.
+    pub median_time_span: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            network: u32::from_be_bytes(*b"cryp"),
+            block_reward: 50 * COIN,
+            halving_interval: 210_000,
+            pow: Pow::Sha256,
+            pow_limit: Target::MAX.to_compact(),
+            initial_bits: Target::from_leading_zero_bits(16).to_compact(),
+            target_block_time: 60,
+            retarget_interval: 144,
+            max_future_drift: 2 * 60 * 60,
+            median_time_span: 11,
+        }
+    }
+}
+
+impl Params {
+    /// the newly created coins a coinbase at `height` may pay, on top of the block's fees.
+    pub fn block_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+
+        if halvings >= u64::BITS as u64 {
+            return 0;
+        }
+
+        self.block_reward >> halvings
+    }
+
+    /// the target after a retarget window of `retarget_interval` blocks that took `timespan` seconds
+    /// from its first to its last block, both in compact form.
+    ///
+    /// the target is scaled by how much longer or shorter the window took than it should have,
+    /// by at most 4 times either way, and never gets easier than `pow_limit`.
+    pub fn retarget(&self, bits: u32, timespan: u64) -> u32 {
+        let expected = self.target_block_time
+            .saturating_mul(self.retarget_interval.max(2) - 1)
+            .max(1);
+
+        let timespan = timespan.clamp(expected / 4, expected.saturating_mul(4));
+        let target = Target::from_compact(bits).mul_div(timespan, expected);
+
+        target
+            .min(Target::from_compact(self.pow_limit))
+            .to_compact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_subsidy() {
+        let params = Params {
+            block_reward: 64,
+            halving_interval: 10,
+            ..Params::default()
+        };
+
+        assert_eq!(params.block_subsidy(0), 64);
+        assert_eq!(params.block_subsidy(9), 64);
+        assert_eq!(params.block_subsidy(10), 32);
+        assert_eq!(params.block_subsidy(25), 16);
+        assert_eq!(params.block_subsidy(60), 1);
+        assert_eq!(params.block_subsidy(70), 0);
+        assert_eq!(params.block_subsidy(u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = Params {
+            target_block_time: 60,
+            retarget_interval: 11,
+            pow_limit: 0x1e00ffff,
+            ..Params::default()
+        };
+
+        // 10 gaps of 60 seconds.
+        assert_eq!(params.retarget(0x1d00ffff, 600), 0x1d00ffff);
+        assert_eq!(params.retarget(0x1d00ffff, 300), 0x1c7fff80);
+        assert_eq!(params.retarget(0x1d00ffff, 1200), 0x1d01fffe);
+
+        // at most 4 times per window.
+        assert_eq!(params.retarget(0x1d00ffff, 0), 0x1c3fffc0);
+        assert_eq!(params.retarget(0x1d00ffff, 6000), 0x1d03fffc);
+
+        assert_eq!(params.retarget(0x1d7fffff, 6000), 0x1e00ffff);
+    }
+}
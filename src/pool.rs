@@ -0,0 +1,466 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::block::{Block, BlockHeader};
+use crate::miner::{MiningJob, Outcome};
+use crate::parallel_miner::CancelToken;
+use crate::params::Params;
+use crate::pow::Pow;
+use crate::protocol;
+use crate::protocol::ProtocolError;
+use crate::util::target::Target;
+
+/// how often a worker checks for a new job while it is mining.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// a worker that does not take a message in this long is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PoolMessage {
+    /// the first message of a worker, its shares are credited to `worker`.
+    Subscribe { worker: String },
+    /// a header to mine with `pow`, any hash that meets `share_bits` is worth a share.
+    /// workers never see the transactions, the merkle root in the header commits to them.
+    Job { id: u64, header: BlockHeader, share_bits: u32, pow: Pow },
+    Submit { job: u64, nonce: u64, timestamp: u64 },
+    Accepted { job: u64 },
+    Rejected { job: u64, reason: String },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShareError {
+    /// the share is for a template that was already replaced.
+    StaleJob { job: u64 },
+    Duplicate,
+    TimestampOutOfRange { found: u64 },
+    InsufficientWork,
+}
+
+impl Display for ShareError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShareError::StaleJob { job } => write!(f, "job {job} is stale"),
+            ShareError::Duplicate => write!(f, "share was already submitted"),
+            ShareError::TimestampOutOfRange { found } => write!(f, "timestamp {found} is out of range"),
+            ShareError::InsufficientWork => write!(f, "hash does not meet the share target"),
+        }
+    }
+}
+
+impl Error for ShareError {}
+
+struct Job {
+    id: u64,
+    block: Block,
+    share_target: Target,
+    /// whether a share already solved the block, later solutions only count as shares.
+    solved: bool,
+}
+
+impl Job {
+    fn message(&self, pow: Pow) -> PoolMessage {
+        PoolMessage::Job {
+            id: self.id,
+            header: self.block.header,
+            share_bits: self.share_target.to_compact(),
+            pow,
+        }
+    }
+}
+
+/// the connection to a worker, locked on its own so a slow worker only holds up itself.
+struct Worker {
+    stream: TcpStream,
+    /// the newest job sent, an older one that comes in late is not sent after it.
+    job: Option<u64>,
+}
+
+impl Worker {
+    fn send_job(&mut self, id: u64, message: &PoolMessage) -> Result<(), ProtocolError> {
+        if self.job.is_some_and(|job| job >= id) {
+            return Ok(());
+        }
+
+        protocol::send(&mut self.stream, message)?;
+        self.job = Some(id);
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    job: Option<Job>,
+    next_job: u64,
+    /// the nonces and timestamps of every share of the current job, so none is counted twice.
+    seen: HashSet<(u64, u64)>,
+    shares: HashMap<String, u64>,
+    workers: HashMap<Uuid, Arc<Mutex<Worker>>>,
+}
+
+/// hands out block templates to workers and counts the shares they find.
+///
+/// a share is a hash that meets an easier target than the block,
+/// so every worker proves how much it mined long before any of them solves the block.
+pub struct Pool {
+    params: Params,
+    share_bits: u32,
+    state: Mutex<State>,
+}
+
+impl Pool {
+    pub fn new(params: Params, share_bits: u32) -> Self {
+        Self {
+            params,
+            share_bits,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// replaces the job every worker is mining, shares for older jobs are rejected from now on.
+    ///
+    /// the job is sent to the workers after the pool is unlocked, so shares keep coming in meanwhile.
+    pub fn set_template(&self, block: Block) -> u64 {
+        let (id, message, workers) = {
+            let mut state = self.state.lock().unwrap();
+
+            let id = state.next_job;
+            state.next_job += 1;
+
+            // a share never takes more work than the block itself.
+            let share_target = Target::from_compact(self.share_bits).max(Target::from_compact(block.header.bits));
+            let job = Job { id, block, share_target, solved: false };
+            let message = job.message(self.params.pow);
+
+            state.job = Some(job);
+            state.seen.clear();
+
+            let workers: Vec<(Uuid, Arc<Mutex<Worker>>)> = state.workers
+                .iter()
+                .map(|(id, worker)| (*id, Arc::clone(worker)))
+                .collect();
+
+            (id, message, workers)
+        };
+
+        for (worker_id, worker) in workers {
+            if let Err(e) = worker.lock().unwrap().send_job(id, &message) {
+                eprintln!("dropping worker {worker_id}: {e}");
+                self.state.lock().unwrap().workers.remove(&worker_id);
+            }
+        }
+
+        id
+    }
+
+    /// checks and credits a share, returns the block if the share also solves it.
+    pub fn submit(&self, worker: &str, job: u64, nonce: u64, timestamp: u64) -> Result<Option<Block>, ShareError> {
+        let mut state = self.state.lock().unwrap();
+        let State { job: current, seen, shares, .. } = &mut *state;
+
+        let current = current
+            .as_mut()
+            .filter(|current| current.id == job)
+            .ok_or(ShareError::StaleJob { job })?;
+
+        let max = UNIX_EPOCH.elapsed().unwrap().as_secs() + self.params.max_future_drift;
+        if timestamp < current.block.header.timestamp || timestamp > max {
+            return Err(ShareError::TimestampOutOfRange { found: timestamp });
+        }
+
+        if seen.contains(&(nonce, timestamp)) {
+            return Err(ShareError::Duplicate);
+        }
+
+        let mut header = current.block.header;
+        header.nonce = nonce;
+        header.timestamp = timestamp;
+
+        let hash = header.get_pow_hash(self.params.pow.algorithm());
+        if !current.share_target.is_met_by(&hash) {
+            return Err(ShareError::InsufficientWork);
+        }
+
+        let solved = !current.solved && Target::from_compact(header.bits).is_met_by(&hash);
+        current.solved |= solved;
+
+        seen.insert((nonce, timestamp));
+        *shares.entry(worker.to_string()).or_default() += 1;
+
+        Ok(solved.then(|| Block { header, transactions: current.block.transactions.clone() }))
+    }
+
+    /// the shares of every worker so far, to split the rewards by.
+    pub fn shares(&self) -> HashMap<String, u64> {
+        self.state.lock().unwrap().shares.clone()
+    }
+
+    /// accepts workers on `addr` in the background, every block they solve is sent to `solved`.
+    pub fn listen<A: ToSocketAddrs>(self: &Arc<Self>, addr: A, solved: Sender<Block>) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        let pool = Arc::clone(self);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+
+                let pool = Arc::clone(&pool);
+                let solved = solved.clone();
+
+                thread::spawn(move || {
+                    let id = Uuid::new_v4();
+
+                    if let Err(e) = pool.handle_worker(id, stream, solved) {
+                        eprintln!("worker {id} disconnected: {e}");
+                    }
+
+                    pool.state.lock().unwrap().workers.remove(&id);
+                });
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    fn handle_worker(&self, id: Uuid, stream: TcpStream, solved: Sender<Block>) -> io::Result<()> {
+        let mut reader = io::BufReader::new(stream.try_clone()?);
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        let PoolMessage::Subscribe { worker } = protocol::recv(&mut reader)? else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a subscribe message"));
+        };
+
+        let writer = Arc::new(Mutex::new(Worker { stream, job: None }));
+
+        // registered first, so a job set in the meantime is sent by `set_template`,
+        // and the current one can only be older.
+        let current = {
+            let mut state = self.state.lock().unwrap();
+            state.workers.insert(id, Arc::clone(&writer));
+            state.job.as_ref().map(|job| (job.id, job.message(self.params.pow)))
+        };
+
+        if let Some((job, message)) = current {
+            writer.lock().unwrap().send_job(job, &message)?;
+        }
+
+        loop {
+            let PoolMessage::Submit { job, nonce, timestamp } = protocol::recv(&mut reader)? else {
+                continue;
+            };
+
+            let reply = match self.submit(&worker, job, nonce, timestamp) {
+                Ok(block) => {
+                    if let Some(block) = block {
+                        let _ = solved.send(block);
+                    }
+
+                    PoolMessage::Accepted { job }
+                }
+                Err(e) => PoolMessage::Rejected { job, reason: e.to_string() },
+            };
+
+            protocol::send(&mut writer.lock().unwrap().stream, &reply)?;
+        }
+    }
+}
+
+/// connects to a pool and mines the jobs it sends, with the algorithm of each job,
+/// until `stop` is cancelled or the pool goes away.
+pub fn run_worker<A: ToSocketAddrs>(addr: A, worker: &str, threads: usize, stop: &CancelToken) -> io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = stream.try_clone()?;
+    protocol::send(&mut writer, &PoolMessage::Subscribe { worker: worker.to_string() })?;
+
+    let (sender, messages) = mpsc::channel();
+    let mut reader = io::BufReader::new(stream);
+    thread::spawn(move || {
+        while let Ok::<PoolMessage, ProtocolError>(message) = protocol::recv(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut current: Option<(u64, Target, Pow, MiningJob)> = None;
+
+    while !stop.is_cancelled() {
+        // without a job there is nothing to do but wait for one.
+        let mut timeout = if current.is_some() { Duration::ZERO } else { POLL_INTERVAL };
+        let mut latest = None;
+
+        // everything the pool sent since the last share, only the newest job is worth mining.
+        loop {
+            match messages.recv_timeout(timeout) {
+                Ok(PoolMessage::Job { id, header, share_bits, pow }) => latest = Some((id, header, share_bits, pow)),
+                Ok(PoolMessage::Rejected { job, reason }) => eprintln!("share for job {job} rejected: {reason}"),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            timeout = Duration::ZERO;
+        }
+
+        if let Some((id, header, share_bits, pow)) = latest {
+            let target = Target::from_compact(share_bits);
+            let job = MiningJob::start_with_target(header, pow, threads, target, 0..u64::MAX);
+            current = Some((id, target, pow, job));
+        }
+
+        let Some((id, target, pow, job)) = current.as_mut() else {
+            continue;
+        };
+
+        match job.poll(POLL_INTERVAL) {
+            None => {}
+            Some(Outcome::Solved(header)) => {
+                protocol::send(&mut writer, &PoolMessage::Submit { job: *id, nonce: header.nonce, timestamp: header.timestamp })?;
+
+                // keep looking for more shares after this one.
+                let nonces = header.nonce.saturating_add(1)..u64::MAX;
+                *job = MiningJob::start_with_target(header, *pow, threads, *target, nonces);
+            }
+            Some(_) => current = None,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wallet::Wallet;
+    use super::*;
+
+    fn template(params: &Params) -> Block {
+        let mut block = Block::genesis();
+        block.header.bits = Target::from_leading_zero_bits(8).to_compact();
+        block.header.timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        block.set_coinbase(&Wallet::from_passphrase("pool").address, params).unwrap();
+
+        block
+    }
+
+    /// a nonce whose hash meets `target` but not `not`, for a block at its own timestamp.
+    fn find_share(block: &Block, target: Target, not: Target) -> u64 {
+        let mut block = block.clone();
+
+        loop {
+            let hash = block.get_hash();
+            if target.is_met_by(&hash) && !not.is_met_by(&hash) {
+                return block.header.nonce;
+            }
+
+            block.header.nonce += 1;
+        }
+    }
+
+    #[test]
+    fn test_submit() {
+        let params = Params::default();
+        let pool = Pool::new(params.clone(), Target::from_leading_zero_bits(4).to_compact());
+        let block = template(&params);
+        let job = pool.set_template(block.clone());
+
+        let share_target = Target::from_leading_zero_bits(4);
+        let block_target = Target::from_compact(block.header.bits);
+
+        let share = find_share(&block, share_target, block_target);
+        assert_eq!(pool.submit("alice", job, share, block.header.timestamp), Ok(None));
+        assert_eq!(pool.submit("alice", job, share, block.header.timestamp), Err(ShareError::Duplicate));
+
+        let bad = find_share(&block, Target::MAX, share_target);
+        assert_eq!(pool.submit("bob", job, bad, block.header.timestamp), Err(ShareError::InsufficientWork));
+        assert_eq!(pool.submit("bob", job, share, block.header.timestamp - 1), Err(ShareError::TimestampOutOfRange { found: block.header.timestamp - 1 }));
+
+        let solution = find_share(&block, block_target, Target::from_bytes([0u8; 32]));
+        let solved = pool.submit("bob", job, solution, block.header.timestamp).unwrap().unwrap();
+        assert_eq!(solved.validate(&params), Ok(()));
+
+        // another solution for the same block is only a share.
+        let mut later = block.clone();
+        later.header.nonce = solution + 1;
+        let other = find_share(&later, block_target, Target::from_bytes([0u8; 32]));
+        assert_eq!(pool.submit("bob", job, other, block.header.timestamp), Ok(None));
+
+        assert_eq!(pool.shares(), HashMap::from([("alice".to_string(), 1), ("bob".to_string(), 2)]));
+
+        let next = pool.set_template(block.clone());
+        assert_eq!(pool.submit("alice", job, share, block.header.timestamp), Err(ShareError::StaleJob { job }));
+        assert_eq!(pool.submit("alice", next, share, block.header.timestamp), Ok(None));
+    }
+
+    #[test]
+    fn test_slow_worker() {
+        let params = Params::default();
+        let pool = Arc::new(Pool::new(params.clone(), Target::from_leading_zero_bits(4).to_compact()));
+        let (sender, _solved) = mpsc::channel();
+        let addr = pool.listen("127.0.0.1:0", sender).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        protocol::send(&mut stream, &PoolMessage::Subscribe { worker: "alice".into() }).unwrap();
+        while pool.state.lock().unwrap().workers.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // a worker stuck in a send.
+        let worker = pool.state.lock().unwrap().workers.values().next().cloned().unwrap();
+        let stuck = worker.lock().unwrap();
+
+        let block = template(&params);
+        let setter = {
+            let pool = Arc::clone(&pool);
+            let block = block.clone();
+            thread::spawn(move || pool.set_template(block))
+        };
+
+        while pool.state.lock().unwrap().job.is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // shares are still taken while the job is on its way to the worker.
+        let share = find_share(&block, Target::from_leading_zero_bits(4), Target::from_compact(block.header.bits));
+        assert_eq!(pool.submit("bob", 0, share, block.header.timestamp), Ok(None));
+
+        drop(stuck);
+        assert_eq!(setter.join().unwrap(), 0);
+
+        let mut reader = io::BufReader::new(stream);
+        assert!(matches!(protocol::recv(&mut reader).unwrap(), PoolMessage::Job { id: 0, .. }));
+    }
+
+    #[test]
+    fn test_worker() {
+        // the worker is told which algorithm to mine with.
+        let params = Params { pow: Pow::DoubleSha256, ..Params::default() };
+        let pool = Arc::new(Pool::new(params.clone(), Target::from_leading_zero_bits(4).to_compact()));
+        let (sender, solved) = mpsc::channel();
+        let addr = pool.listen("127.0.0.1:0", sender).unwrap();
+
+        pool.set_template(template(&params));
+
+        let stop = CancelToken::new();
+        let worker_stop = stop.clone();
+        let worker = thread::spawn(move || run_worker(addr, "alice", 1, &worker_stop));
+
+        let block = solved.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(block.validate(&params), Ok(()));
+        assert!(pool.shares()["alice"] >= 1);
+
+        stop.cancel();
+        worker.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::block::{NONCE_OFFSET, PAYLOAD_SIZE};
+use crate::crypto::sha256;
+use crate::crypto::sha256::Midstate;
+
+/// a function a block payload is hashed with to prove the work that went into it.
+pub trait PowAlgorithm: Send + Sync {
+    fn hash(&self, payload: &[u8; PAYLOAD_SIZE]) -> [u8; 32];
+
+    /// hashes the payload with one nonce after another, for the miner.
+    ///
+    /// the default hashes the whole payload every time,
+    /// algorithms override it to reuse the work that does not depend on the nonce.
+    fn nonce_hasher(&self, mut payload: [u8; PAYLOAD_SIZE]) -> Box<dyn FnMut(u64) -> [u8; 32] + '_> {
+        Box::new(move |nonce| {
+            payload[NONCE_OFFSET..].copy_from_slice(&nonce.to_be_bytes());
+            self.hash(&payload)
+        })
+    }
+
+    /// how many nonces the miner hashes before it looks whether it should stop,
+    /// slow algorithms look more often so a cancelled search stops quickly.
+    fn check_interval(&self) -> u64 {
+        256
+    }
+}
+
+// the nonce has to be in the last block of the payload for the midstate to be reused.
+const _: () = assert!(NONCE_OFFSET / 64 == (PAYLOAD_SIZE - 1) / 64);
+
+pub struct Sha256;
+
+impl PowAlgorithm for Sha256 {
+    fn hash(&self, payload: &[u8; PAYLOAD_SIZE]) -> [u8; 32] {
+        sha256::hash(payload)
+    }
+
+    fn nonce_hasher(&self, payload: [u8; PAYLOAD_SIZE]) -> Box<dyn FnMut(u64) -> [u8; 32] + '_> {
+        let mut midstate = Midstate::new(&payload);
+
+        Box::new(move |nonce| {
+            midstate.update(NONCE_OFFSET, &nonce.to_be_bytes());
+            midstate.hash()
+        })
+    }
+}
+
+/// sha256 applied twice, like bitcoin.
+pub struct DoubleSha256;
+
+impl PowAlgorithm for DoubleSha256 {
+    fn hash(&self, payload: &[u8; PAYLOAD_SIZE]) -> [u8; 32] {
+        sha256::hash(sha256::hash(payload))
+    }
+
+    fn nonce_hasher(&self, payload: [u8; PAYLOAD_SIZE]) -> Box<dyn FnMut(u64) -> [u8; 32] + '_> {
+        let mut midstate = Midstate::new(&payload);
+
+        Box::new(move |nonce| {
+            midstate.update(NONCE_OFFSET, &nonce.to_be_bytes());
+            sha256::hash(midstate.hash())
+        })
+    }
+}
+
+/// scrypt with the payload as both password and salt, with the parameters litecoin uses.
+///
+/// every hash needs 128 KiB of memory, which takes away most of the edge of specialised hardware.
+pub struct Scrypt;
+
+impl PowAlgorithm for Scrypt {
+    fn hash(&self, payload: &[u8; PAYLOAD_SIZE]) -> [u8; 32] {
+        // N = 1024, r = 1, p = 1.
+        let params = scrypt::Params::new(10, 1, 1, 32).unwrap();
+
+        let mut hash = [0u8; 32];
+        scrypt::scrypt(payload, payload, &params, &mut hash).unwrap();
+
+        hash
+    }
+
+    // a hash takes long enough that looking after every one costs nothing.
+    fn check_interval(&self) -> u64 {
+        1
+    }
+}
+
+/// the proof of work algorithms a chain can be started with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pow {
+    #[default]
+    Sha256,
+    DoubleSha256,
+    Scrypt,
+}
+
+impl Pow {
+    pub fn algorithm(self) -> &'static dyn PowAlgorithm {
+        match self {
+            Pow::Sha256 => &Sha256,
+            Pow::DoubleSha256 => &DoubleSha256,
+            Pow::Scrypt => &Scrypt,
+        }
+    }
+}
+
+impl FromStr for Pow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Pow::Sha256),
+            "sha256d" => Ok(Pow::DoubleSha256),
+            "scrypt" => Ok(Pow::Scrypt),
+            _ => Err(format!("unknown proof of work algorithm: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use super::*;
+
+    #[test]
+    fn test_nonce_hasher() {
+        let mut block = Block::genesis();
+
+        for pow in [Pow::Sha256, Pow::DoubleSha256, Pow::Scrypt] {
+            let algorithm = pow.algorithm();
+            let mut hasher = algorithm.nonce_hasher(block.get_payload());
+
+            for nonce in [0, 1, u64::MAX] {
+                block.header.nonce = nonce;
+                assert_eq!(hasher(nonce), algorithm.hash(&block.get_payload()), "{pow:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_algorithms_differ() {
+        let payload = Block::genesis().get_payload();
+
+        let sha256 = Sha256.hash(&payload);
+        assert_eq!(sha256, sha256::hash(payload));
+        assert_eq!(DoubleSha256.hash(&payload), sha256::hash(sha256));
+        assert_ne!(Scrypt.hash(&payload), sha256);
+    }
+}
@@ -1,9 +1,76 @@
+use std::net::SocketAddr;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::block::{Block, BlockHeader};
+use crate::transaction::Transaction;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// the most headers sent in one `Headers` message.
+pub const MAX_HEADERS: u32 = 2000;
+
+/// the most blocks asked for in one `GetBlocks` message, a node that asks for more is dropped.
+pub const MAX_BLOCKS_PER_REQUEST: usize = 500;
+
+/// the most addresses sent in one `Addr` message.
+pub const MAX_ADDRS: usize = 1000;
+
+/// the version of the protocol this node speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// the oldest version it still talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// the node serves headers and blocks of its chain.
+pub const SERVICE_CHAIN: u64 = 1 << 0;
+
+/// what a node says about itself when it connects, before any other message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub version: u32,
+    pub network: u32,
+    /// the hash of the first block, none while the node has no blocks.
+    pub genesis: Option<[u8; 32]>,
+    /// how many blocks the node has.
+    pub height: u64,
+    pub node: Uuid,
+    /// the port the node accepts connections on, none if it does not.
+    pub listen_port: Option<u16>,
+    /// the `SERVICE_` flags of what the node offers.
+    pub services: u64,
+}
+
+/// a block or transaction, by its hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Inventory {
+    Block([u8; 32]),
+    Transaction([u8; 32]),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Message {
+    /// opens the handshake, both sides send it first and answer the other's with a `Verack`.
+    Version(Version),
+    Verack,
+    /// the connection is closed because of `reason`.
+    Reject { reason: String },
+
     Echo,
     Text(String),
+
+    /// asks for addresses of other nodes, answered with an `Addr`.
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+
+    /// announces blocks and transactions the sender has, the receiver asks for the ones it is missing.
+    Inventory(Vec<Inventory>),
+    /// asks for up to `count` headers of the chain, starting at height `start`.
+    GetHeaders { start: u64, count: u32 },
+    Headers(Vec<BlockHeader>),
+    /// asks for up to `MAX_BLOCKS_PER_REQUEST` blocks by hash, every one is answered with a `Block`
+    /// or a `NotFound`, in order.
+    GetBlocks(Vec<[u8; 32]>),
+    GetTransaction([u8; 32]),
+    Block(Block),
+    Transaction(Transaction),
+    NotFound(Inventory),
 }
 
 #[cfg(test)]
@@ -25,6 +92,35 @@ mod tests {
 
         eprintln!("msg = {:?}", msg);
     }
+
+    #[test]
+    fn test_serde_chain_data() {
+        let block = Block::genesis();
+        let messages = [
+            Message::Version(Version {
+                version: PROTOCOL_VERSION,
+                network: 1,
+                genesis: Some(block.get_hash()),
+                height: 1,
+                node: Uuid::new_v4(),
+                listen_port: Some(1111),
+                services: SERVICE_CHAIN,
+            }),
+            Message::Verack,
+            Message::Addr(vec![SocketAddr::from(([127, 0, 0, 1], 1111))]),
+            Message::Inventory(vec![Inventory::Block(block.get_hash()), Inventory::Transaction([1u8; 32])]),
+            Message::GetHeaders { start: 0, count: MAX_HEADERS },
+            Message::Headers(vec![block.header]),
+            Message::GetBlocks(vec![block.get_hash()]),
+            Message::Block(block.clone()),
+            Message::NotFound(Inventory::Transaction([1u8; 32])),
+        ];
+
+        for msg in messages {
+            let buf = rmp_serde::to_vec(&msg).unwrap();
+            assert_eq!(rmp_serde::from_slice::<Message>(&buf).unwrap(), msg);
+        }
+    }
 }
 
 // impl From<&Message> for u8 {
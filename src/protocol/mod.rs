@@ -1,6 +1,9 @@
 pub mod message;
+#[allow(clippy::module_inception)]
 pub mod protocol;
 pub mod peer;
+pub mod peer_table;
+pub mod seen;
 
 pub use message::Message;
-pub use protocol::{send, recv};
\ No newline at end of file
+pub use protocol::{send, recv, ProtocolError};
\ No newline at end of file
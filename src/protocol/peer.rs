@@ -1,92 +1,1578 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::{io, thread};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::sync::{Arc, Mutex};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
-use crate::protocol::message::Message;
+use crate::block::{Block, BlockError, BlockHeader};
+use crate::chain::{Chain, ChainError};
+use crate::crypto::sha256;
+use crate::mempool::{Mempool, MempoolError};
+use crate::protocol;
+use crate::protocol::ProtocolError;
+use crate::protocol::message::{Inventory, Message, Version, MAX_ADDRS, MAX_BLOCKS_PER_REQUEST, MAX_HEADERS, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SERVICE_CHAIN};
+use crate::protocol::peer_table::PeerTable;
+use crate::protocol::seen::Seen;
+use crate::transaction::Transaction;
 
-type Clients = Arc<Mutex<HashMap<Uuid, TcpStream>>>;
+/// how long `sync` waits for the peer to answer a request.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// how long either side of a new connection waits for the other's part of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// how long a send to a client may block before the client is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// how often the dialer looks for addresses to connect to.
+const DIAL_INTERVAL: Duration = Duration::from_secs(1);
+/// the node dials new addresses while it has fewer connections than this.
+const TARGET_CONNECTIONS: usize = 8;
+/// how many message, block and transaction hashes the node remembers to not take them in twice.
+const SEEN_CAPACITY: usize = 10_000;
+/// how many inventory items every client is remembered to have.
+const KNOWN_CAPACITY: usize = 5_000;
+/// the longest text that is passed on.
+const MAX_TEXT_LEN: usize = 1024;
+/// how far below the tip a branch may fork off to be switched to, deeper ones are not followed.
+const MAX_FORK_DEPTH: u64 = 100;
+/// how many blocks off the chain are kept while the rest of their branch comes in.
+const MAX_SIDE_BLOCKS: usize = 2 * MAX_FORK_DEPTH as usize;
+/// the most new addresses taken from one client, so a single node can not fill the peer table.
+const MAX_ADDRS_PER_CLIENT: usize = 100;
 
+type Clients = Arc<Mutex<HashMap<Uuid, Client>>>;
+/// messages to pass on, with the client they came from.
+type Relay = Sender<(Uuid, Message)>;
+
+/// a connection that finished its handshake.
+struct Client {
+    /// locked for every message, so the ones sent from different threads do not interleave.
+    stream: Arc<Mutex<TcpStream>>,
+    /// inventory the client announced or was sent, it is not announced to it again.
+    known: Seen<Inventory>,
+    /// how many new addresses it added to the peer table.
+    addrs: usize,
+}
+
+#[derive(Debug)]
+pub enum PeerError {
+    Io(io::Error),
+    Protocol(ProtocolError),
+    Chain(ChainError),
+    /// a header does not follow the one before it, or does not carry its work.
+    InvalidHeader { index: u64, error: BlockError },
+    /// the peer sent a different block than the header it announced.
+    UnexpectedBlock { index: u64 },
+    NotFound(Inventory),
+    UnsupportedVersion { version: u32 },
+    WrongNetwork { network: u32 },
+    WrongGenesis,
+    /// the node connected to itself.
+    SelfConnection,
+    HandshakeTimeout,
+    /// a message came before the handshake was done.
+    UnexpectedMessage,
+    /// the other side closed the connection because of `reason`.
+    Rejected { reason: String },
+    /// a header does not follow a block of the chain, at most `MAX_FORK_DEPTH` below the tip.
+    Unconnected { index: u64 },
+}
+
+impl Display for PeerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerError::Io(e) => write!(f, "io error: {e}"),
+            PeerError::Protocol(e) => write!(f, "{e}"),
+            PeerError::Chain(e) => write!(f, "{e}"),
+            PeerError::InvalidHeader { index, error } => write!(f, "header {index} rejected: {error}"),
+            PeerError::UnexpectedBlock { index } => write!(f, "block {index} does not match its header"),
+            PeerError::NotFound(item) => write!(f, "peer does not have {item:?}"),
+            PeerError::UnsupportedVersion { version } => write!(f, "protocol version {version} is not supported, the oldest is {MIN_PROTOCOL_VERSION}"),
+            PeerError::WrongNetwork { network } => write!(f, "peer is on network {network:#010x}"),
+            PeerError::WrongGenesis => write!(f, "peer's chain has another genesis block"),
+            PeerError::SelfConnection => write!(f, "connected to itself"),
+            PeerError::HandshakeTimeout => write!(f, "handshake timed out"),
+            PeerError::UnexpectedMessage => write!(f, "unexpected message before the handshake was done"),
+            PeerError::Rejected { reason } => write!(f, "rejected by peer: {reason}"),
+            PeerError::Unconnected { index } => write!(f, "header {index} does not follow the chain"),
+        }
+    }
+}
+
+impl Error for PeerError {}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> Self {
+        PeerError::Io(e)
+    }
+}
+
+impl From<ProtocolError> for PeerError {
+    fn from(e: ProtocolError) -> Self {
+        PeerError::Protocol(e)
+    }
+}
+
+impl From<ChainError> for PeerError {
+    fn from(e: ChainError) -> Self {
+        PeerError::Chain(e)
+    }
+}
+
+/// checks the other side of a handshake can talk to us.
+fn check_version(ours: &Version, theirs: &Version) -> Result<(), PeerError> {
+    if theirs.version < MIN_PROTOCOL_VERSION {
+        return Err(PeerError::UnsupportedVersion { version: theirs.version });
+    }
+
+    if theirs.network != ours.network {
+        return Err(PeerError::WrongNetwork { network: theirs.network });
+    }
+
+    // a node without blocks can still join either chain.
+    if let (Some(ours), Some(theirs)) = (ours.genesis, theirs.genesis) {
+        if ours != theirs {
+            return Err(PeerError::WrongGenesis);
+        }
+    }
+
+    if theirs.node == ours.node {
+        return Err(PeerError::SelfConnection);
+    }
+
+    Ok(())
+}
+
+fn recv_handshake(reader: &mut io::BufReader<TcpStream>) -> Result<Message, PeerError> {
+    match protocol::recv(reader) {
+        Ok(Message::Reject { reason }) => Err(PeerError::Rejected { reason }),
+        Ok(msg) => Ok(msg),
+        Err(ProtocolError::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Err(PeerError::HandshakeTimeout),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn exchange_versions(reader: &mut io::BufReader<TcpStream>, writer: &mut TcpStream, ours: &Version) -> Result<Version, PeerError> {
+    protocol::send(&mut *writer, &Message::Version(ours.clone()))?;
+
+    let Message::Version(theirs) = recv_handshake(reader)? else {
+        return Err(PeerError::UnexpectedMessage);
+    };
+
+    check_version(ours, &theirs)?;
+    protocol::send(&mut *writer, &Message::Verack)?;
+
+    match recv_handshake(reader)? {
+        Message::Verack => Ok(theirs),
+        _ => Err(PeerError::UnexpectedMessage),
+    }
+}
+
+/// sends our version and waits for the other side's, which does the same, then both acknowledge.
+///
+/// nothing else may be sent before, a side that gets anything else, or a version it can not
+/// talk to, sends a `Reject` with the reason and drops the connection.
+pub fn handshake(reader: &mut io::BufReader<TcpStream>, writer: &mut TcpStream, ours: &Version) -> Result<Version, PeerError> {
+    let timeout = reader.get_ref().read_timeout()?;
+    reader.get_ref().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let result = exchange_versions(reader, writer, ours);
+
+    match &result {
+        Ok(_) => reader.get_ref().set_read_timeout(timeout)?,
+        // they already know, or are not listening any more.
+        Err(PeerError::Rejected { .. } | PeerError::Io(_) | PeerError::Protocol(ProtocolError::Io(_))) => {}
+        Err(e) => {
+            let _ = protocol::send(&mut *writer, &Message::Reject { reason: e.to_string() });
+        }
+    }
+
+    result
+}
+
+/// identifies a message by its encoding.
+fn message_hash(msg: &Message) -> [u8; 32] {
+    sha256::hash(rmp_serde::to_vec(msg).unwrap_or_default())
+}
+
+/// what to send after a message from a client was handled.
+#[derive(Debug, Default, PartialEq)]
+struct Response {
+    /// sent back to the client the message came from, in order.
+    replies: Vec<Message>,
+    /// passed on to every other client.
+    relay: Option<Message>,
+    /// the client broke the protocol, it is sent a `Reject` with the reason and dropped.
+    reject: Option<String>,
+}
+
+impl Response {
+    fn reply(msg: Message) -> Self {
+        Self {
+            replies: vec![msg],
+            ..Self::default()
+        }
+    }
+
+    fn relay(msg: Message) -> Self {
+        Self {
+            relay: Some(msg),
+            ..Self::default()
+        }
+    }
+
+    fn reject(reason: String) -> Self {
+        Self {
+            reject: Some(reason),
+            ..Self::default()
+        }
+    }
+}
+
+/// a node on the network, serving its chain and mempool to the clients that connect to it,
+/// and connecting to the addresses it knows until it has `TARGET_CONNECTIONS`.
+///
+/// clones share the same clients, chain, mempool and peer table.
+#[derive(Clone)]
 pub struct Peer {
     pub id: Uuid,
     clients: Clients,
+    chain: Arc<Mutex<Chain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    table: Arc<Mutex<PeerTable>>,
+    listen_port: Arc<OnceLock<u16>>,
+    /// hashes of the texts, blocks and transactions that came in recently.
+    seen: Arc<Mutex<Seen<[u8; 32]>>>,
+    /// told about every block a client gets onto the chain.
+    tip_watchers: Arc<Mutex<Vec<Sender<Block>>>>,
+    /// valid blocks of a branch that is not longer than the chain yet, by hash.
+    side_blocks: Arc<Mutex<HashMap<[u8; 32], Block>>>,
 }
 
 impl Peer {
-    pub fn new() -> Peer {
+    pub fn new(chain: Chain) -> Peer {
         Peer {
             id: Uuid::new_v4(),
             clients: Arc::new(Mutex::new(HashMap::new())),
+            chain: Arc::new(Mutex::new(chain)),
+            mempool: Arc::new(Mutex::new(Mempool::new())),
+            table: Arc::new(Mutex::new(PeerTable::new())),
+            listen_port: Arc::new(OnceLock::new()),
+            seen: Arc::new(Mutex::new(Seen::new(SEEN_CAPACITY))),
+            tip_watchers: Arc::new(Mutex::new(Vec::new())),
+            side_blocks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// adds an address to dial, like a seed node from the config.
+    pub fn add_address(&self, addr: SocketAddr) {
+        self.table.lock().unwrap().add(addr);
+    }
+
+    /// the addresses the node knows and how connecting to them went.
+    pub fn table(&self) -> Arc<Mutex<PeerTable>> {
+        Arc::clone(&self.table)
+    }
+
+    /// how many nodes it is connected to, in either direction.
+    pub fn connections(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// the chain the peer serves, shared with anything else that appends to it.
+    pub fn chain(&self) -> Arc<Mutex<Chain>> {
+        Arc::clone(&self.chain)
+    }
+
+    pub fn mempool(&self) -> Arc<Mutex<Mempool>> {
+        Arc::clone(&self.mempool)
+    }
+
+    /// sends every block that a sync or a client connects to the chain to `sender`,
+    /// so a miner knows when its template is stale.
+    pub fn watch_tip(&self, sender: Sender<Block>) {
+        self.tip_watchers.lock().unwrap().push(sender);
+    }
+
+    fn tip_changed(&self, block: &Block) {
+        self.tip_watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| watcher.send(block.clone()).is_ok());
+    }
+
+    /// what this node tells the ones it connects to in the handshake.
+    pub fn version(&self) -> Version {
+        let chain = self.chain.lock().unwrap();
+
+        Version {
+            version: PROTOCOL_VERSION,
+            network: chain.params().network,
+            genesis: chain.get_header(0).map(|header| header.get_hash()),
+            height: chain.len(),
+            node: self.id,
+            listen_port: self.listen_port.get().copied(),
+            services: SERVICE_CHAIN,
         }
     }
 
-    fn _t_broadcast(recv: Receiver<Message>, clients: Clients) {
+    /// sends a message of this node to every node it is connected to.
+    pub fn broadcast(&self, msg: &Message) {
+        // so it is not taken in again when it comes back around.
+        if let Message::Text(_) = msg {
+            self.seen.lock().unwrap().insert(message_hash(msg));
+        }
+
+        self.relay(None, msg);
+    }
+
+    /// sends a message on to every client but the one it came from.
+    /// inventory is only announced to the clients that are not known to have it.
+    ///
+    /// the clients lock is only held to pick the recipients, a slow client holds up
+    /// the relay for at most `WRITE_TIMEOUT` and is dropped after.
+    fn relay(&self, from: Option<Uuid>, msg: &Message) {
+        let mut recipients = Vec::new();
+
+        for (id, client) in self.clients.lock().unwrap().iter_mut().filter(|(id, _)| Some(**id) != from) {
+            let msg = match msg {
+                Message::Inventory(items) => {
+                    let items: Vec<Inventory> = items
+                        .iter()
+                        .copied()
+                        .filter(|item| client.known.insert(*item))
+                        .collect();
+
+                    if items.is_empty() {
+                        continue;
+                    }
+
+                    Message::Inventory(items)
+                }
+                msg => msg.clone(),
+            };
+
+            recipients.push((*id, Arc::clone(&client.stream), msg));
+        }
+
+        for (id, stream, msg) in recipients {
+            let mut stream = stream.lock().unwrap();
+
+            if let Err(e) = protocol::send(&mut *stream, &msg) {
+                eprintln!("dropping client {}: {}", id, e);
+                // its connection thread sees the stream closed and removes it.
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
+
+    fn _t_broadcast(&self, recv: Receiver<(Uuid, Message)>) {
         loop {
             let res = recv.recv();
-            let msg = res.unwrap();
+            let (from, msg) = res.unwrap();
+
+            self.relay(Some(from), &msg);
+        }
+    }
+
+    /// remembers that the client has the items, so they are not announced to it.
+    fn mark_known(&self, id: Uuid, items: impl IntoIterator<Item = Inventory>) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            for item in items {
+                client.known.insert(item);
+            }
+        }
+    }
+
+    /// answers requests from the chain and the mempool, and takes in the blocks and transactions it is sent.
+    ///
+    /// only what is new and valid is passed on, as an inventory announcement for blocks and transactions.
+    fn handle_message(&self, from: Uuid, msg: Message) -> Response {
+        match msg {
+            Message::Version(_) | Message::Verack => {
+                eprintln!("ignoring a handshake message after the handshake");
+                Response::default()
+            }
+            Message::Reject { reason } => {
+                eprintln!("rejected by peer: {reason}");
+                Response::default()
+            }
+            // a keep alive between two nodes, it is not passed on.
+            Message::Echo => Response::default(),
+            Message::Text(ref text) => {
+                if text.is_empty() || text.len() > MAX_TEXT_LEN {
+                    return Response::default();
+                }
+
+                // a text has no hash of its own, it is passed on the first time it comes in.
+                if !self.seen.lock().unwrap().insert(message_hash(&msg)) {
+                    return Response::default();
+                }
+
+                Response::relay(msg)
+            }
+            Message::GetAddr => {
+                let addrs = self.table.lock().unwrap().addresses(MAX_ADDRS);
+                Response::reply(Message::Addr(addrs))
+            }
+            Message::Addr(addrs) => {
+                let allowed = self.clients
+                    .lock()
+                    .unwrap()
+                    .get(&from)
+                    .map_or(MAX_ADDRS_PER_CLIENT, |client| MAX_ADDRS_PER_CLIENT.saturating_sub(client.addrs));
 
-            let mut clients = clients.lock().unwrap();
+                let mut table = self.table.lock().unwrap();
+                let added = addrs
+                    .into_iter()
+                    .take(MAX_ADDRS)
+                    .filter(|addr| table.add(*addr))
+                    .take(allowed)
+                    .count();
+                drop(table);
 
-            for (id, client) in clients.iter_mut() {
-                match crate::protocol::send(client, &msg) {
-                    Ok(_) => println!("sent {:?} to {:}", msg, id),
-                    Err(e) => println!("error sending {:?} to {:}: {:?}", msg, id, e),
+                if let Some(client) = self.clients.lock().unwrap().get_mut(&from) {
+                    client.addrs += added;
                 }
+
+                Response::default()
+            }
+            Message::Inventory(items) => {
+                self.mark_known(from, items.iter().copied());
+                self.handle_inventory(items)
+            }
+            Message::GetHeaders { start, count } => {
+                let chain = self.chain.lock().unwrap();
+                let end = start
+                    .saturating_add(count.min(MAX_HEADERS) as u64)
+                    .min(chain.len());
+
+                let headers = (start..end)
+                    .filter_map(|height| chain.get_header(height))
+                    .collect();
+
+                Response::reply(Message::Headers(headers))
+            }
+            Message::Headers(headers) => self.handle_headers(headers),
+            Message::GetBlocks(hashes) => {
+                if hashes.len() > MAX_BLOCKS_PER_REQUEST {
+                    return Response::reject(format!(
+                        "asked for {} blocks, the most is {MAX_BLOCKS_PER_REQUEST}",
+                        hashes.len()
+                    ));
+                }
+
+                let mut requested = HashSet::new();
+
+                // the chain is locked for one block at a time, so a large request does not hold up appends.
+                let replies = hashes
+                    .into_iter()
+                    .filter(|hash| requested.insert(*hash))
+                    .map(|hash| match self.chain.lock().unwrap().get_by_hash(&hash) {
+                        Ok(Some(block)) => Message::Block(block),
+                        Ok(None) => Message::NotFound(Inventory::Block(hash)),
+                        Err(e) => {
+                            eprintln!("could not read block {}: {e}", hex::encode(hash));
+                            Message::NotFound(Inventory::Block(hash))
+                        }
+                    })
+                    .collect();
+
+                Response { replies, ..Response::default() }
+            }
+            Message::GetTransaction(hash) => {
+                let reply = match self.mempool.lock().unwrap().get(&hash) {
+                    Some(txn) => Message::Transaction(txn.clone()),
+                    None => Message::NotFound(Inventory::Transaction(hash)),
+                };
+
+                Response::reply(reply)
+            }
+            Message::Block(block) => {
+                self.mark_known(from, [Inventory::Block(block.get_hash())]);
+                self.handle_block(block)
+            }
+            Message::Transaction(txn) => {
+                self.mark_known(from, [Inventory::Transaction(txn.hash)]);
+                self.handle_transaction(txn)
+            }
+            Message::NotFound(item) => {
+                eprintln!("peer does not have {item:?}");
+                Response::default()
             }
         }
     }
 
-    fn _handle_stream(id: Uuid, stream: TcpStream, send: Sender<Message>) -> thread::JoinHandle<()> {
-        let mut reader = io::BufReader::new(stream);
+    /// asks for the blocks of headers that make the chain longer, on top of the tip or on a branch
+    /// off a block below it, and for the headers after a full batch.
+    /// headers that do not carry their work or do not follow each other get the client dropped.
+    fn handle_headers(&self, headers: Vec<BlockHeader>) -> Response {
+        if headers.len() > MAX_HEADERS as usize {
+            return Response::reject(format!("sent {} headers, the most is {MAX_HEADERS}", headers.len()));
+        }
+
+        let chain = self.chain.lock().unwrap();
+
+        let (known, missing) = match check_headers(&chain, &headers) {
+            Ok(result) => result,
+            // the chain moved on since they were asked for, or the client is on a branch too far back.
+            Err(PeerError::Unconnected { .. }) => return Response::default(),
+            Err(e) => return Response::reject(e.to_string()),
+        };
+
+        // a branch that is not longer than the chain is not switched to, its blocks are not needed.
+        let longer = headers
+            .get(known)
+            .is_some_and(|first| first.index + missing.len() as u64 > chain.len());
+
+        if !longer {
+            return Response::default();
+        }
+
+        drop(chain);
+
+        let mut replies: Vec<Message> = missing
+            .chunks(MAX_BLOCKS_PER_REQUEST)
+            .map(|hashes| Message::GetBlocks(hashes.to_vec()))
+            .collect();
+
+        // a full batch, there may be more after it. asked for after the blocks,
+        // so they are connected by the time the next headers come in.
+        if let Some(last) = headers.last().filter(|_| headers.len() == MAX_HEADERS as usize) {
+            let Some(start) = last.index.checked_add(1) else {
+                return Response::reject(format!("header index {} is the last there is", last.index));
+            };
+
+            replies.push(Message::GetHeaders { start, count: MAX_HEADERS });
+        }
+
+        Response { replies, ..Response::default() }
+    }
+
+    /// asks for every announced block and transaction that did not come in recently.
+    fn handle_inventory(&self, items: Vec<Inventory>) -> Response {
+        let chain = self.chain.lock().unwrap();
+        let mempool = self.mempool.lock().unwrap();
+        let seen = self.seen.lock().unwrap();
+
+        let mut replies = Vec::new();
+        let mut blocks = Vec::new();
+
+        for item in items {
+            match item {
+                Inventory::Block(hash) if !chain.contains(&hash) && !seen.contains(&hash) => blocks.push(hash),
+                Inventory::Transaction(hash) if !mempool.contains(&hash) && !seen.contains(&hash) => {
+                    replies.push(Message::GetTransaction(hash))
+                }
+                _ => {}
+            }
+        }
+
+        replies.extend(
+            blocks
+                .chunks(MAX_BLOCKS_PER_REQUEST)
+                .map(|hashes| Message::GetBlocks(hashes.to_vec()))
+        );
+
+        Response { replies, ..Response::default() }
+    }
+
+    fn handle_block(&self, block: Block) -> Response {
+        let hash = block.get_hash();
+
+        // an invalid block is not asked for again when it is announced, one that came too early
+        // is still taken when it is asked for by its header.
+        self.seen.lock().unwrap().insert(hash);
+
+        let mut chain = self.chain.lock().unwrap();
+
+        if chain.contains(&hash) {
+            return Response::default();
+        }
+
+        match chain.append(&block) {
+            Ok(()) => {
+                self.mempool.lock().unwrap().connect_block(&block, chain.ledger());
+                self.tip_changed(&block);
+                Response::relay(Message::Inventory(vec![Inventory::Block(hash)]))
+            }
+            // the block is not on top of the tip, it may be on another branch.
+            Err(ChainError::InvalidBlock { error: BlockError::UnexpectedIndex { .. } | BlockError::ParentMismatch, .. }) => {
+                self.handle_side_block(&mut chain, block)
+            }
+            Err(e) => {
+                eprintln!("rejected block {}: {e}", block.get_digest());
+                Response::default()
+            }
+        }
+    }
+
+    /// keeps a block that is not on top of the tip, and switches the chain to its branch
+    /// once that is longer. when the blocks before it are missing, the headers to find them are asked for.
+    fn handle_side_block(&self, chain: &mut Chain, block: Block) -> Response {
+        if let Err(e) = block.validate(chain.params()) {
+            eprintln!("rejected block {}: {e}", block.get_digest());
+            return Response::default();
+        }
+
+        let mut side_blocks = self.side_blocks.lock().unwrap();
+
+        // back from the block through the kept ones, to the block of the chain it branches off.
+        let mut branch = vec![block];
+        while let Some(first) = branch.last().map(|block| block.header).filter(|first| !follows_chain(chain, first)) {
+            match side_blocks.get(&first.parent_hash) {
+                Some(parent) if branch.len() <= side_blocks.len() => branch.push(parent.clone()),
+                _ => {
+                    keep_side_block(&mut side_blocks, branch.swap_remove(0));
+                    let start = chain.len().saturating_sub(MAX_FORK_DEPTH);
+                    return Response::reply(Message::GetHeaders { start, count: MAX_HEADERS });
+                }
+            }
+        }
+
+        branch.reverse();
+
+        let tip = branch[branch.len() - 1].get_hash();
+        if branch[0].header.index + branch.len() as u64 <= chain.len() {
+            keep_side_block(&mut side_blocks, branch.pop().unwrap());
+            return Response::default();
+        }
+
+        for block in &branch {
+            side_blocks.remove(&block.get_hash());
+        }
+
+        drop(side_blocks);
+
+        match self.reorganize(chain, &branch) {
+            Ok(()) => Response::relay(Message::Inventory(vec![Inventory::Block(tip)])),
+            Err(e) => {
+                eprintln!("rejected branch to {}: {e}", hex::encode(tip));
+                Response::default()
+            }
+        }
+    }
+
+    /// switches the chain to a longer branch. the transactions of the blocks it takes off
+    /// go back into the mempool, unless the branch has them or made them invalid.
+    fn reorganize(&self, chain: &mut Chain, branch: &[Block]) -> Result<(), ChainError> {
+        let disconnected = chain.reorganize(branch)?;
+        let mut mempool = self.mempool.lock().unwrap();
+
+        for block in branch {
+            mempool.connect_block(block, chain.ledger());
+        }
+
+        for txn in disconnected.iter().flat_map(|block| block.transactions.iter().skip(1)) {
+            let _ = mempool.insert(txn.clone(), chain.ledger());
+        }
+
+        if let Some(tip) = branch.last() {
+            self.tip_changed(tip);
+        }
+
+        Ok(())
+    }
+
+    fn handle_transaction(&self, txn: Transaction) -> Response {
+        let hash = txn.hash;
+
+        if !self.seen.lock().unwrap().insert(hash) {
+            return Response::default();
+        }
+
+        let chain = self.chain.lock().unwrap();
+
+        match self.mempool.lock().unwrap().insert(txn, chain.ledger()) {
+            Ok(()) => Response::relay(Message::Inventory(vec![Inventory::Transaction(hash)])),
+            Err(MempoolError::Duplicate) => Response::default(),
+            Err(e) => {
+                eprintln!("rejected transaction {}: {e}", hex::encode(hash));
+                Response::default()
+            }
+        }
+    }
+
+    /// sends the replies to one client, under its stream lock so they do not interleave with a broadcast.
+    fn reply(&self, id: Uuid, replies: &[Message]) -> Result<(), ProtocolError> {
+        let Some(stream) = self.clients.lock().unwrap().get(&id).map(|client| Arc::clone(&client.stream)) else {
+            return Ok(());
+        };
+
+        let mut stream = stream.lock().unwrap();
+
+        for msg in replies {
+            protocol::send(&mut *stream, msg)?;
+        }
+
+        Ok(())
+    }
+
+    /// a handshake with an address that was dialed did not work, it is tried again later unless it never will.
+    fn handshake_failed(&self, addr: SocketAddr, error: &PeerError) {
+        let mut table = self.table.lock().unwrap();
+
+        match error {
+            PeerError::UnsupportedVersion { .. } | PeerError::WrongNetwork { .. } | PeerError::WrongGenesis | PeerError::SelfConnection => {
+                table.ban(addr)
+            }
+            _ => table.failed(addr, Instant::now()),
+        }
+    }
+
+    /// runs a connection from the handshake until it closes, `dialed` is the address of an outbound one.
+    fn _handle_stream(&self, id: Uuid, stream: TcpStream, send: Relay, dialed: Option<SocketAddr>) -> thread::JoinHandle<()> {
+        let peer = self.clone();
 
         thread::spawn(move || {
+            let writer = stream
+                .try_clone()
+                .and_then(|writer| writer.set_write_timeout(Some(WRITE_TIMEOUT)).map(|_| writer));
+
+            let mut writer = match writer {
+                Ok(writer) => writer,
+                Err(e) => {
+                    eprintln!("dropping client {}: {}", id, e);
+                    if let Some(addr) = dialed {
+                        peer.table.lock().unwrap().failed(addr, Instant::now());
+                    }
+
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    return;
+                }
+            };
+            let remote_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+            let mut reader = io::BufReader::new(stream);
+
+            // the client only gets messages once it finished the handshake.
+            let version = match handshake(&mut reader, &mut writer, &peer.version()) {
+                Ok(version) => version,
+                Err(e) => {
+                    eprintln!("dropping client {}: {}", id, e);
+                    if let Some(addr) = dialed {
+                        peer.handshake_failed(addr, &e);
+                    }
+
+                    let _ = writer.shutdown(std::net::Shutdown::Both);
+                    return;
+                }
+            };
+
+            println!("client {} is node {} at height {}", id, version.node, version.height);
+
+            // an inbound connection comes from a random port, the node listens on the one it told us.
+            let addr = dialed.or(remote_ip.zip(version.listen_port).map(SocketAddr::from));
+            if let Some(addr) = addr {
+                let mut table = peer.table.lock().unwrap();
+                table.add(addr);
+                table.connected(addr);
+            }
+
+            let client = Client {
+                stream: Arc::new(Mutex::new(writer)),
+                known: Seen::new(KNOWN_CAPACITY),
+                addrs: 0,
+            };
+            peer.clients.lock().unwrap().insert(id, client);
+
+            let mut greeting = Vec::new();
+
+            if dialed.is_some() {
+                greeting.push(Message::GetAddr);
+            }
+
+            // from below the tip, in case the other node's chain branches off ours.
+            let height = peer.chain.lock().unwrap().len();
+            if version.height > height {
+                greeting.push(Message::GetHeaders { start: height.saturating_sub(MAX_FORK_DEPTH), count: MAX_HEADERS });
+            }
+
+            if let Err(e) = peer.reply(id, &greeting) {
+                eprintln!("could not greet {}: {:?}", id, e);
+            }
+
+            let mut misbehaved = false;
+
             loop {
-                if let Ok::<Message, io::Error>(msg) = crate::protocol::recv(&mut reader) {
-                    println!("received message from {}: {:?}", id, msg);
-                    send.send(msg).expect("could not send message to broadcast thread");
-                } else {
+                let msg: Message = match crate::protocol::recv(&mut reader) {
+                    Ok(msg) => msg,
+                    Err(e) if e.is_recoverable() => {
+                        eprintln!("skipping a message from {}: {}", id, e);
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("could not read from {}: {}", id, e);
+                        break;
+                    }
+                };
+
+                let response = peer.handle_message(id, msg);
+
+                if let Err(e) = peer.reply(id, &response.replies) {
+                    eprintln!("could not reply to {}: {:?}", id, e);
                     break;
                 }
+
+                if let Some(reason) = response.reject {
+                    eprintln!("dropping client {}: {}", id, reason);
+                    let _ = peer.reply(id, &[Message::Reject { reason }]);
+                    misbehaved = true;
+                    break;
+                }
+
+                if let Some(msg) = response.relay {
+                    send.send((id, msg)).expect("could not send message to broadcast thread");
+                }
             }
 
             eprintln!("client {} disconnected", id);
-            reader.into_inner()
-                .shutdown(std::net::Shutdown::Both)
-                .expect("could not shutdown stream");
+            peer.clients.lock().unwrap().remove(&id);
+
+            if let Some(addr) = addr {
+                let mut table = peer.table.lock().unwrap();
+
+                // it counts against the address like a failed handshake.
+                if misbehaved {
+                    table.failed(addr, Instant::now());
+                } else {
+                    table.disconnected(addr, Instant::now());
+                }
+            }
+
+            // the client may already have closed its end.
+            let _ = reader.into_inner().shutdown(std::net::Shutdown::Both);
         })
     }
 
-    fn _t_listener<T: ToSocketAddrs>(addr: T, send: Sender<Message>, clients: Clients) {
-        let listener = TcpListener::bind(addr).unwrap();
-
+    fn _t_listener(&self, listener: TcpListener, send: Relay) {
         for stream in listener.incoming() {
-            let id = Uuid::new_v4();
-            let stream = stream.expect("TODO fix");
-            let writer = stream.try_clone().expect("TODO fix");
+            // a connection that failed before it was accepted only concerns that client.
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("could not accept a connection: {}", e);
+                    continue;
+                }
+            };
+
+            self._handle_stream(Uuid::new_v4(), stream, send.clone(), None);
+        }
+    }
 
-            clients.lock().unwrap().insert(id.clone(), writer);
+    fn dial(&self, addr: SocketAddr, send: Relay) {
+        let peer = self.clone();
+
+        thread::spawn(move || match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                peer._handle_stream(Uuid::new_v4(), stream, send, Some(addr));
+            }
+            Err(e) => {
+                eprintln!("could not connect to {}: {}", addr, e);
+                peer.table.lock().unwrap().failed(addr, Instant::now());
+            }
+        });
+    }
 
-            Peer::_handle_stream(id.clone(), stream, send.clone());
+    /// the next address to dial, none while there are enough connections.
+    fn next_to_dial(&self) -> Option<SocketAddr> {
+        let mut table = self.table.lock().unwrap();
+
+        if table.active() >= TARGET_CONNECTIONS {
+            return None;
+        }
+
+        table.next_to_dial(Instant::now())
+    }
+
+    fn _t_dialer(&self, send: Relay) {
+        loop {
+            while let Some(addr) = self.next_to_dial() {
+                self.dial(addr, send.clone());
+            }
+
+            thread::sleep(DIAL_INTERVAL);
         }
     }
 
-    pub fn run<T: 'static + ToSocketAddrs + Send + Sync>(
+    /// listens on `addr` and serves clients in the background, returns the address it is bound to.
+    ///
+    /// the addresses in the peer table are dialed in the background too, and tried again
+    /// with a growing backoff whenever a connection fails or closes.
+    pub fn run<T: ToSocketAddrs>(
         &self,
         addr: T
-    ) -> io::Result<(thread::JoinHandle<()>, thread::JoinHandle<()>)> {
-        let (send, recv) = std::sync::mpsc::channel::<Message>();
+    ) -> io::Result<(SocketAddr, thread::JoinHandle<()>, thread::JoinHandle<()>)> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let _ = self.listen_port.set(local_addr.port());
+
+        let (send, recv) = std::sync::mpsc::channel();
 
-        let listener_clients = self.clients.clone();
+        let peer = self.clone();
+        let dialer_send = send.clone();
+        thread::spawn(move || {
+            peer._t_dialer(dialer_send)
+        });
+
+        let peer = self.clone();
         let listen_handle = thread::spawn(move || {
-            Peer::_t_listener(addr, send, listener_clients)
+            peer._t_listener(listener, send)
         });
 
-        let broadcast_clients = self.clients.clone();
+        let peer = self.clone();
         let broadcast_handle = thread::spawn(move || {
-            Peer::_t_broadcast(recv, broadcast_clients)
+            peer._t_broadcast(recv)
         });
 
-        Ok((listen_handle, broadcast_handle))
+        Ok((local_addr, listen_handle, broadcast_handle))
+    }
+
+    /// downloads the blocks the peer at `addr` has that make our chain longer, returns how many were added.
+    ///
+    /// headers come first, they are checked to follow our chain and carry their work
+    /// before any of their blocks is asked for. the blocks are then fully validated as they are appended.
+    /// when the peer's chain branches off below our tip, ours is switched to it once all of the branch came in.
+    pub fn sync<A: ToSocketAddrs>(&self, addr: A) -> Result<u64, PeerError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(SYNC_TIMEOUT))?;
+
+        let mut writer = stream.try_clone()?;
+        let mut reader = io::BufReader::new(stream);
+        handshake(&mut reader, &mut writer, &self.version())?;
+
+        let mut added = 0;
+        let mut start = self.chain.lock().unwrap().len().saturating_sub(MAX_FORK_DEPTH);
+
+        loop {
+            protocol::send(&mut writer, &Message::GetHeaders { start, count: MAX_HEADERS })?;
+
+            // anything else the peer sends in the meantime, like its broadcasts, is skipped.
+            let headers = loop {
+                if let Message::Headers(headers) = protocol::recv(&mut reader)? {
+                    break headers;
+                }
+            };
+
+            if headers.is_empty() {
+                return Ok(added);
+            }
+
+            let (known, hashes) = check_headers(&self.chain.lock().unwrap(), &headers)?;
+            let missing = &headers[known..];
+
+            let len = self.chain.lock().unwrap().len();
+            let fork = missing.first().map(|first| first.index).filter(|index| *index < len);
+
+            // the peer is on a branch that is not longer than ours.
+            if fork.is_some_and(|fork| fork + missing.len() as u64 <= len) {
+                return Ok(added);
+            }
+
+            let mut branch = Vec::new();
+
+            for (batch, hashes) in missing.chunks(MAX_BLOCKS_PER_REQUEST).zip(hashes.chunks(MAX_BLOCKS_PER_REQUEST)) {
+                protocol::send(&mut writer, &Message::GetBlocks(hashes.to_vec()))?;
+
+                for (header, hash) in batch.iter().zip(hashes) {
+                    let block = loop {
+                        match protocol::recv(&mut reader)? {
+                            Message::Block(block) => break block,
+                            Message::NotFound(item) => return Err(PeerError::NotFound(item)),
+                            _ => {}
+                        }
+                    };
+
+                    if block.get_hash() != *hash {
+                        return Err(PeerError::UnexpectedBlock { index: header.index });
+                    }
+
+                    if fork.is_some() {
+                        branch.push(block);
+                        continue;
+                    }
+
+                    let mut chain = self.chain.lock().unwrap();
+                    chain.append(&block)?;
+                    self.mempool.lock().unwrap().connect_block(&block, chain.ledger());
+                    self.tip_changed(&block);
+                    added += 1;
+                }
+            }
+
+            if !branch.is_empty() {
+                self.reorganize(&mut self.chain.lock().unwrap(), &branch)?;
+                added += branch.len() as u64;
+            }
+
+            if headers.len() < MAX_HEADERS as usize {
+                return Ok(added);
+            }
+
+            start = self.chain.lock().unwrap().len();
+        }
+    }
+}
+
+/// whether the header goes right after a block of the chain, the tip or one at most `MAX_FORK_DEPTH` below it.
+fn follows_chain(chain: &Chain, header: &BlockHeader) -> bool {
+    if header.index > chain.len() || header.index + MAX_FORK_DEPTH < chain.len() {
+        return false;
+    }
+
+    // the first block of a chain has an all zero parent.
+    match header.index.checked_sub(1) {
+        Some(height) => chain.get_header(height).map(|parent| parent.get_hash()) == Some(header.parent_hash),
+        None => header.parent_hash == [0u8; 32],
+    }
+}
+
+/// keeps a block of a branch that is not longer than the chain yet, the lowest one goes first when full.
+fn keep_side_block(side_blocks: &mut HashMap<[u8; 32], Block>, block: Block) {
+    if side_blocks.len() >= MAX_SIDE_BLOCKS {
+        let lowest = side_blocks
+            .iter()
+            .min_by_key(|(_, block)| block.header.index)
+            .map(|(hash, _)| *hash);
+
+        if let Some(lowest) = lowest {
+            side_blocks.remove(&lowest);
+        }
+    }
+
+    side_blocks.insert(block.get_hash(), block);
+}
+
+/// checks the headers follow a block of the chain, then one another, and carry their work.
+/// the ones the chain already has are skipped, returns how many that were and the hashes of the rest.
+fn check_headers(chain: &Chain, headers: &[BlockHeader]) -> Result<(usize, Vec<[u8; 32]>), PeerError> {
+    let known = headers
+        .iter()
+        .take_while(|header| chain.contains(&header.get_hash()))
+        .count();
+
+    let Some(first) = headers.get(known) else {
+        return Ok((known, Vec::new()));
+    };
+
+    if !follows_chain(chain, first) {
+        return Err(PeerError::Unconnected { index: first.index });
+    }
+
+    let mut parent = first.parent_hash;
+    let mut index = first.index;
+
+    let hashes = headers[known..]
+        .iter()
+        .map(|header| {
+            let invalid = |error| PeerError::InvalidHeader { index: header.index, error };
+
+            if header.index != index {
+                return Err(invalid(BlockError::UnexpectedIndex { expected: index, found: header.index }));
+            }
+
+            if header.parent_hash != parent {
+                return Err(invalid(BlockError::ParentMismatch));
+            }
+
+            header.validate(chain.params()).map_err(invalid)?;
+
+            parent = header.get_hash();
+            index += 1;
+
+            Ok(parent)
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok((known, hashes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::params::Params;
+    use crate::test_util::{mine_block, mine_blocks, params, temp_dir};
+    use crate::util::target::Target;
+    use crate::wallet::Wallet;
+    use super::*;
+
+    /// a chain of `genesis` and a block of its own, with a transaction of alice's in it.
+    fn fork_chain(dir: &Path, genesis: &Block) -> (Chain, Transaction) {
+        let mut chain = Chain::open(dir, params()).unwrap();
+        chain.append(genesis).unwrap();
+
+        let txn = Transaction::new(&Wallet::from_passphrase("alice"), &Wallet::from_passphrase("bob").address, 10, 1, 0);
+        mine_block(&mut chain, std::slice::from_ref(&txn));
+
+        (chain, txn)
+    }
+
+    #[test]
+    fn test_handle_requests() {
+        let dir = temp_dir();
+        let mut chain = Chain::open(&dir, params()).unwrap();
+        mine_blocks(&mut chain, 2);
+
+        let first = chain.get_by_height(0).unwrap().unwrap();
+        let peer = Peer::new(chain);
+
+        let Response { replies, relay, .. } = peer.handle_message(Uuid::nil(), Message::GetHeaders { start: 1, count: 10 });
+        let tip = peer.chain().lock().unwrap().get_header(1).unwrap();
+        assert_eq!(replies, vec![Message::Headers(vec![tip])]);
+        assert_eq!(relay, None);
+
+        let unknown = [9u8; 32];
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::GetBlocks(vec![first.get_hash(), unknown])).replies,
+            vec![Message::Block(first.clone()), Message::NotFound(Inventory::Block(unknown))]
+        );
+
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::GetTransaction(unknown)).replies,
+            vec![Message::NotFound(Inventory::Transaction(unknown))]
+        );
+
+        // a hash asked for twice is answered once.
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::GetBlocks(vec![unknown, unknown])).replies,
+            vec![Message::NotFound(Inventory::Block(unknown))]
+        );
+
+        let oversize = peer.handle_message(Uuid::nil(), Message::GetBlocks(vec![unknown; MAX_BLOCKS_PER_REQUEST + 1]));
+        assert!(oversize.replies.is_empty());
+        assert!(oversize.reject.is_some());
+
+        let inventory = vec![Inventory::Block(first.get_hash()), Inventory::Block(unknown), Inventory::Transaction(unknown)];
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::Inventory(inventory)).replies,
+            vec![Message::GetTransaction(unknown), Message::GetBlocks(vec![unknown])]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_handle_headers() {
+        let (source_dir, dir) = (temp_dir(), temp_dir());
+        let mut source = Chain::open(&source_dir, params()).unwrap();
+        mine_blocks(&mut source, 2);
+        let headers = vec![source.get_header(0).unwrap(), source.get_header(1).unwrap()];
+        let hashes: Vec<[u8; 32]> = headers.iter().map(BlockHeader::get_hash).collect();
+
+        let peer = Peer::new(Chain::open(&dir, params()).unwrap());
+
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::Headers(headers.clone())),
+            Response::reply(Message::GetBlocks(hashes))
+        );
+
+        // headers off another branch are not asked for, but are no reason to drop the client.
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::Headers(headers[1..].to_vec())), Response::default());
+
+        let mut unworked = headers.clone();
+        while Target::from_compact(unworked[1].bits).is_met_by(&unworked[1].get_hash()) {
+            unworked[1].nonce += 1;
+        }
+
+        for invalid in [unworked, vec![headers[0]; 2], vec![headers[0]; MAX_HEADERS as usize + 1]] {
+            let response = peer.handle_message(Uuid::nil(), Message::Headers(invalid));
+            assert!(response.replies.is_empty());
+            assert!(response.reject.is_some());
+        }
+
+        std::fs::remove_dir_all(source_dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_handle_block() {
+        let (source_dir, dir) = (temp_dir(), temp_dir());
+        let mut source = Chain::open(&source_dir, params()).unwrap();
+        mine_blocks(&mut source, 2);
+
+        let peer = Peer::new(Chain::open(&dir, params()).unwrap());
+        let first = source.get_by_height(0).unwrap().unwrap();
+        let second = source.get_by_height(1).unwrap().unwrap();
+
+        let (sender, tips) = std::sync::mpsc::channel();
+        peer.watch_tip(sender);
+
+        // a block past the tip makes the peer ask for what it is missing.
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::Block(second.clone())),
+            Response::reply(Message::GetHeaders { start: 0, count: MAX_HEADERS })
+        );
+
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::Block(first.clone())),
+            Response::relay(Message::Inventory(vec![Inventory::Block(first.get_hash())]))
+        );
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::Block(first.clone())), Response::default());
+        assert_eq!(peer.chain().lock().unwrap().len(), 1);
+
+        // only the block that made it onto the chain, once.
+        assert_eq!(tips.try_iter().map(|block| block.get_hash()).collect::<Vec<_>>(), vec![first.get_hash()]);
+
+        // alice has the first block's reward to spend now.
+        let txn = Transaction::new(&Wallet::from_passphrase("alice"), &Wallet::from_passphrase("bob").address, 10, 1, 0);
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::Transaction(txn.clone())),
+            Response::relay(Message::Inventory(vec![Inventory::Transaction(txn.hash)]))
+        );
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::GetTransaction(txn.hash)).replies, vec![Message::Transaction(txn)]);
+
+        std::fs::remove_dir_all(source_dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_addr_limit() {
+        let dir = temp_dir();
+        let peer = Peer::new(Chain::open(&dir, params()).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let id = Uuid::new_v4();
+        peer.clients.lock().unwrap().insert(id, Client { stream: Arc::new(Mutex::new(stream)), known: Seen::new(KNOWN_CAPACITY), addrs: 0 });
+
+        let addrs = |ports: std::ops::Range<u16>| ports.map(|port| SocketAddr::from(([10, 0, 0, 1], port))).collect();
+
+        // addresses the table already has do not count.
+        peer.add_address(SocketAddr::from(([10, 0, 0, 1], 1)));
+        peer.handle_message(id, Message::Addr(addrs(1..80)));
+        assert_eq!(peer.table().lock().unwrap().len(), 79);
+
+        peer.handle_message(id, Message::Addr(addrs(80..200)));
+        assert_eq!(peer.table().lock().unwrap().len(), MAX_ADDRS_PER_CLIENT + 1);
+
+        // another node still gets its own share.
+        peer.handle_message(Uuid::nil(), Message::Addr(addrs(200..210)));
+        assert_eq!(peer.table().lock().unwrap().len(), MAX_ADDRS_PER_CLIENT + 11);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let dir = temp_dir();
+        let peer = Peer::new(Chain::open(&dir, params()).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        let stream = Arc::new(Mutex::new(stream));
+        peer.clients.lock().unwrap().insert(Uuid::new_v4(), Client { stream: Arc::clone(&stream), known: Seen::new(KNOWN_CAPACITY), addrs: 0 });
+
+        // a client stuck in a send.
+        let stuck = stream.lock().unwrap();
+
+        let text = Message::Text("hello".into());
+        let broadcaster = {
+            let peer = peer.clone();
+            let text = text.clone();
+            thread::spawn(move || peer.broadcast(&text))
+        };
+
+        // the other clients can still be looked at and replied to meanwhile.
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(peer.connections(), 1);
+        assert!(peer.reply(Uuid::nil(), &[Message::Echo]).is_ok());
+
+        drop(stuck);
+        broadcaster.join().unwrap();
+
+        let mut reader = io::BufReader::new(remote);
+        let msg: Message = protocol::recv(&mut reader).unwrap();
+        assert_eq!(msg, text);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_handle_fork() {
+        let (source_dir, dir) = (temp_dir(), temp_dir());
+        let mut source = Chain::open(&source_dir, params()).unwrap();
+        mine_blocks(&mut source, 3);
+        let blocks: Vec<Block> = (0..3).map(|height| source.get_by_height(height).unwrap().unwrap()).collect();
+        let headers: Vec<BlockHeader> = blocks.iter().map(|block| block.header).collect();
+
+        let (chain, txn) = fork_chain(&dir, &blocks[0]);
+        let peer = Peer::new(chain);
+        let (sender, tips) = std::sync::mpsc::channel();
+        peer.watch_tip(sender);
+
+        // only the blocks of a branch that is longer are asked for.
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::Headers(headers[..2].to_vec())), Response::default());
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::Headers(headers.clone())),
+            Response::reply(Message::GetBlocks(vec![blocks[1].get_hash(), blocks[2].get_hash()]))
+        );
+
+        // the branch is kept until it is longer, then the chain switches to it.
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::Block(blocks[1].clone())), Response::default());
+        assert_eq!(peer.chain().lock().unwrap().len(), 2);
+
+        assert_eq!(
+            peer.handle_message(Uuid::nil(), Message::Block(blocks[2].clone())),
+            Response::relay(Message::Inventory(vec![Inventory::Block(blocks[2].get_hash())]))
+        );
+        assert_eq!(peer.chain().lock().unwrap().tip_hash(), Some(blocks[2].get_hash()));
+        assert_eq!(tips.try_iter().map(|block| block.get_hash()).collect::<Vec<_>>(), vec![blocks[2].get_hash()]);
+        assert!(peer.mempool().lock().unwrap().contains(&txn.hash));
+        assert!(peer.side_blocks.lock().unwrap().is_empty());
+
+        // a block whose parent is unknown makes the peer look for where it branches off.
+        let other_dir = temp_dir();
+        let other = Peer::new(fork_chain(&other_dir, &blocks[0]).0);
+        assert_eq!(
+            other.handle_message(Uuid::nil(), Message::Block(blocks[2].clone())),
+            Response::reply(Message::GetHeaders { start: 0, count: MAX_HEADERS })
+        );
+
+        std::fs::remove_dir_all(source_dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(other_dir).unwrap();
+    }
+
+    #[test]
+    fn test_handle_text() {
+        let dir = temp_dir();
+        let peer = Peer::new(Chain::open(&dir, params()).unwrap());
+        let text = Message::Text("hello".into());
+
+        assert_eq!(peer.handle_message(Uuid::nil(), text.clone()), Response::relay(text.clone()));
+        assert_eq!(peer.handle_message(Uuid::nil(), text), Response::default());
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::Text(String::new())), Response::default());
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::Text("x".repeat(MAX_TEXT_LEN + 1))), Response::default());
+        assert_eq!(peer.handle_message(Uuid::nil(), Message::Echo), Response::default());
+
+        // our own text is not taken in again when it comes back around.
+        let text = Message::Text("bye".into());
+        peer.broadcast(&text);
+        assert_eq!(peer.handle_message(Uuid::nil(), text), Response::default());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync() {
+        let (source_dir, dir) = (temp_dir(), temp_dir());
+        let mut chain = Chain::open(&source_dir, params()).unwrap();
+        mine_blocks(&mut chain, 3);
+
+        let source = Peer::new(chain);
+        let (addr, _, _) = source.run("127.0.0.1:0").unwrap();
+
+        let peer = Peer::new(Chain::open(&dir, params()).unwrap());
+        assert_eq!(peer.sync(addr).unwrap(), 3);
+        assert_eq!(peer.chain().lock().unwrap().tip_hash(), source.chain().lock().unwrap().tip_hash());
+
+        // nothing new the second time around.
+        assert_eq!(peer.sync(addr).unwrap(), 0);
+
+        // a node that mined a second block of its own switches to the source's longer chain,
+        // the transaction of its own block goes back into its mempool.
+        let other_dir = temp_dir();
+        let genesis = source.chain().lock().unwrap().get_by_height(0).unwrap().unwrap();
+        let (chain, txn) = fork_chain(&other_dir, &genesis);
+
+        let other = Peer::new(chain);
+        assert_eq!(other.sync(addr).unwrap(), 2);
+        assert_eq!(other.chain().lock().unwrap().tip_hash(), source.chain().lock().unwrap().tip_hash());
+        assert!(other.mempool().lock().unwrap().contains(&txn.hash));
+
+        std::fs::remove_dir_all(source_dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(other_dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_version() {
+        let ours = Version {
+            version: PROTOCOL_VERSION,
+            network: 1,
+            genesis: Some([1u8; 32]),
+            height: 10,
+            node: Uuid::new_v4(),
+            listen_port: None,
+            services: SERVICE_CHAIN,
+        };
+
+        let theirs = Version { genesis: None, height: 0, node: Uuid::new_v4(), ..ours.clone() };
+        assert!(check_version(&ours, &theirs).is_ok());
+
+        let old = Version { version: MIN_PROTOCOL_VERSION - 1, ..theirs.clone() };
+        assert!(matches!(check_version(&ours, &old), Err(PeerError::UnsupportedVersion { version: 0 })));
+
+        let other = Version { network: 2, ..theirs.clone() };
+        assert!(matches!(check_version(&ours, &other), Err(PeerError::WrongNetwork { network: 2 })));
+
+        let fork = Version { genesis: Some([2u8; 32]), ..theirs.clone() };
+        assert!(matches!(check_version(&ours, &fork), Err(PeerError::WrongGenesis)));
+
+        assert!(matches!(check_version(&ours, &ours), Err(PeerError::SelfConnection)));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (source_dir, dir) = (temp_dir(), temp_dir());
+        let source = Peer::new(Chain::open(&source_dir, params()).unwrap());
+        let (addr, _, _) = source.run("127.0.0.1:0").unwrap();
+
+        // anything before the handshake gets the connection dropped, with the reason.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+        protocol::send(&mut stream, &Message::Text("hello".into())).unwrap();
+        assert!(matches!(protocol::recv(&mut reader).unwrap(), Message::Version(_)));
+        assert!(matches!(protocol::recv(&mut reader).unwrap(), Message::Reject { .. }));
+        assert!(protocol::recv::<_, Message>(&mut reader).is_err());
+
+        assert!(matches!(source.sync(addr), Err(PeerError::SelfConnection)));
+
+        let other = Peer::new(Chain::open(&dir, Params { network: 1, ..params() }).unwrap());
+        assert!(matches!(other.sync(addr), Err(PeerError::WrongNetwork { network }) if network == params().network));
+
+        std::fs::remove_dir_all(source_dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// waits up to 10 seconds for `done`.
+    fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while !done() {
+            if Instant::now() > deadline {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        true
     }
-}
\ No newline at end of file
+
+    /// a bare connection to `addr`, after the handshake.
+    fn connect(addr: SocketAddr) -> (io::BufReader<TcpStream>, TcpStream) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            network: params().network,
+            genesis: None,
+            height: 0,
+            node: Uuid::new_v4(),
+            listen_port: None,
+            services: 0,
+        };
+
+        handshake(&mut reader, &mut stream, &version).unwrap();
+        (reader, stream)
+    }
+
+    #[test]
+    fn test_gossip() {
+        let dir = temp_dir();
+        let mut chain = Chain::open(&dir, params()).unwrap();
+        mine_blocks(&mut chain, 1);
+
+        let peer = Peer::new(chain);
+        let (addr, _, _) = peer.run("127.0.0.1:0").unwrap();
+
+        let (mut x_reader, mut x) = connect(addr);
+        let (mut y_reader, mut y) = connect(addr);
+        assert!(wait_for(|| peer.connections() == 2));
+
+        // a text is passed on once, and not back to where it came from.
+        protocol::send(&mut x, &Message::Text("hello".into())).unwrap();
+        protocol::send(&mut x, &Message::Text("hello".into())).unwrap();
+        protocol::send(&mut x, &Message::Text("bye".into())).unwrap();
+        assert_eq!(protocol::recv::<_, Message>(&mut y_reader).unwrap(), Message::Text("hello".into()));
+        assert_eq!(protocol::recv::<_, Message>(&mut y_reader).unwrap(), Message::Text("bye".into()));
+
+        // a valid transaction is announced, not sent as it is.
+        let txn = Transaction::new(&Wallet::from_passphrase("alice"), &Wallet::from_passphrase("bob").address, 10, 1, 0);
+        let inventory = Message::Inventory(vec![Inventory::Transaction(txn.hash)]);
+        protocol::send(&mut x, &Message::Transaction(txn.clone())).unwrap();
+        assert_eq!(protocol::recv::<_, Message>(&mut y_reader).unwrap(), inventory);
+
+        // announcing it back is not answered with a request for it.
+        protocol::send(&mut y, &inventory).unwrap();
+        protocol::send(&mut y, &Message::GetTransaction(txn.hash)).unwrap();
+        assert_eq!(protocol::recv::<_, Message>(&mut y_reader).unwrap(), Message::Transaction(txn));
+
+        // the sender got none of it.
+        let unknown = [9u8; 32];
+        protocol::send(&mut x, &Message::GetTransaction(unknown)).unwrap();
+        assert_eq!(
+            protocol::recv::<_, Message>(&mut x_reader).unwrap(),
+            Message::NotFound(Inventory::Transaction(unknown))
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_outbound() {
+        let dirs = [temp_dir(), temp_dir(), temp_dir()];
+        let mut chain = Chain::open(&dirs[0], params()).unwrap();
+        mine_blocks(&mut chain, 2);
+
+        let source = Peer::new(chain);
+        let (source_addr, _, _) = source.run("127.0.0.1:0").unwrap();
+
+        // a fresh node with the source as its seed connects to it and catches up.
+        let peer = Peer::new(Chain::open(&dirs[1], params()).unwrap());
+        peer.add_address(source_addr);
+        let (peer_addr, _, _) = peer.run("127.0.0.1:0").unwrap();
+
+        assert!(wait_for(|| peer.chain().lock().unwrap().len() == 2));
+        assert_eq!(peer.chain().lock().unwrap().tip_hash(), source.chain().lock().unwrap().tip_hash());
+        assert_eq!(peer.table().lock().unwrap().score(&source_addr), Some(1));
+
+        // the source learned where the peer listens from its handshake.
+        assert!(wait_for(|| source.table().lock().unwrap().contains(&peer_addr)));
+
+        // a third node only knows the peer, and learns about the source from it.
+        let other = Peer::new(Chain::open(&dirs[2], params()).unwrap());
+        other.add_address(peer_addr);
+        other.run("127.0.0.1:0").unwrap();
+
+        assert!(wait_for(|| other.table().lock().unwrap().contains(&source_addr)));
+        assert!(wait_for(|| other.connections() >= 2));
+
+        for dir in dirs {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let dir = temp_dir();
+        let peer = Peer::new(Chain::open(&dir, params()).unwrap());
+
+        // nothing listens there yet.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        peer.add_address(addr);
+        peer.run("127.0.0.1:0").unwrap();
+        assert!(wait_for(|| peer.table().lock().unwrap().score(&addr) == Some(-1)));
+
+        // it is tried again after the backoff, and connects once the node is up.
+        let source_dir = temp_dir();
+        let source = Peer::new(Chain::open(&source_dir, params()).unwrap());
+        source.run(addr).unwrap();
+
+        assert!(wait_for(|| peer.connections() == 1));
+
+        // dialing itself gets it banned.
+        let own_addr = SocketAddr::from(([127, 0, 0, 1], *peer.listen_port.get().unwrap()));
+        peer.add_address(own_addr);
+        assert!(wait_for(|| peer.table().lock().unwrap().addresses(10) == vec![addr]));
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(source_dir).unwrap();
+    }
+}
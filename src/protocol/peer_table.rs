@@ -0,0 +1,283 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// the most addresses kept, once the table is full a new one replaces the worst idle one
+/// that never connected.
+pub const MAX_ADDRESSES: usize = 1000;
+
+/// the wait before the first reconnect, doubled with every failure after it.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+const MAX_SCORE: i32 = 100;
+/// addresses at or below it are never dialed or passed on again.
+const BAN_SCORE: i32 = -100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// goes up with every handshake that worked, down with every one that did not.
+    score: i32,
+    /// failed attempts since the last handshake that worked.
+    failures: u32,
+    retry_at: Option<Instant>,
+    state: State,
+}
+
+impl Default for Entry {
+    fn default() -> Self {
+        Self {
+            score: 0,
+            failures: 0,
+            retry_at: None,
+            state: State::Idle,
+        }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    BASE_BACKOFF.saturating_mul(1 << doublings).min(MAX_BACKOFF)
+}
+
+/// every address the node knows of, with how connecting to it went so far.
+#[derive(Debug, Default)]
+pub struct PeerTable {
+    entries: HashMap<SocketAddr, Entry>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.entries.contains_key(addr)
+    }
+
+    pub fn score(&self, addr: &SocketAddr) -> Option<i32> {
+        self.entries.get(addr).map(|entry| entry.score)
+    }
+
+    /// remembers an address to dial, returns whether it was new.
+    pub fn add(&mut self, addr: SocketAddr) -> bool {
+        if self.entries.contains_key(&addr) {
+            return false;
+        }
+
+        if self.entries.len() >= MAX_ADDRESSES && !self.evict() {
+            return false;
+        }
+
+        self.entries.insert(addr, Entry::default());
+        true
+    }
+
+    /// makes room for a new address by dropping the worst scored idle one. addresses that ever
+    /// had a handshake work are kept, and so are banned ones, or gossip would bring them back
+    /// with a clean score. returns whether one was dropped.
+    fn evict(&mut self) -> bool {
+        let worst = self.entries
+            .iter()
+            .filter(|(_, entry)| entry.state == State::Idle && entry.score <= 0 && entry.score > BAN_SCORE)
+            .min_by_key(|(addr, entry)| (entry.score, Reverse(entry.failures), **addr))
+            .map(|(addr, _)| *addr);
+
+        match worst {
+            Some(addr) => self.entries.remove(&addr).is_some(),
+            None => false,
+        }
+    }
+
+    /// how many connections are open or being opened.
+    pub fn active(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.state != State::Idle)
+            .count()
+    }
+
+    /// the best scored address that is not connected, banned or waiting out its backoff,
+    /// it counts as connecting from now on.
+    pub fn next_to_dial(&mut self, now: Instant) -> Option<SocketAddr> {
+        let (addr, entry) = self.entries
+            .iter_mut()
+            .filter(|(_, entry)| entry.state == State::Idle && entry.score > BAN_SCORE)
+            .filter(|(_, entry)| entry.retry_at.is_none_or(|retry_at| retry_at <= now))
+            .max_by_key(|(addr, entry)| (entry.score, Reverse(**addr)))?;
+
+        entry.state = State::Connecting;
+        Some(*addr)
+    }
+
+    /// the handshake with `addr` worked, in either direction.
+    pub fn connected(&mut self, addr: SocketAddr) {
+        let entry = self.entries.entry(addr).or_default();
+        entry.state = State::Connected;
+        entry.failures = 0;
+        entry.retry_at = None;
+        entry.score = (entry.score + 1).min(MAX_SCORE);
+    }
+
+    /// the connection closed, it is dialed again once the backoff is over.
+    pub fn disconnected(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.state = State::Idle;
+            entry.failures += 1;
+            entry.retry_at = Some(now + backoff(entry.failures));
+        }
+    }
+
+    /// dialing `addr` or the handshake with it did not work.
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        self.disconnected(addr, now);
+
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.score = (entry.score - 1).max(BAN_SCORE);
+        }
+    }
+
+    /// `addr` is on another network, or is this node, it is never dialed again.
+    pub fn ban(&mut self, addr: SocketAddr) {
+        let entry = self.entries.entry(addr).or_default();
+        entry.state = State::Idle;
+        entry.score = BAN_SCORE;
+    }
+
+    /// up to `limit` addresses worth passing on to other nodes, the best first.
+    pub fn addresses(&self, limit: usize) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &Entry)> = self.entries
+            .iter()
+            .filter(|(_, entry)| entry.score > BAN_SCORE)
+            .collect();
+
+        addrs.sort_by_key(|(addr, entry)| (Reverse(entry.score), **addr));
+
+        addrs
+            .into_iter()
+            .take(limit)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(2), BASE_BACKOFF * 2);
+        assert_eq!(backoff(4), BASE_BACKOFF * 8);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_dial_order() {
+        let now = Instant::now();
+        let mut table = PeerTable::new();
+
+        assert!(table.add(addr(1)));
+        assert!(table.add(addr(2)));
+        assert!(!table.add(addr(1)));
+
+        // the one that worked before comes first.
+        table.connected(addr(2));
+        table.disconnected(addr(2), now - MAX_BACKOFF);
+        assert_eq!(table.next_to_dial(now), Some(addr(2)));
+        assert_eq!(table.next_to_dial(now), Some(addr(1)));
+        assert_eq!(table.next_to_dial(now), None);
+        assert_eq!(table.active(), 2);
+
+        // every failure doubles the wait before the next try.
+        table.failed(addr(1), now);
+        assert_eq!(table.score(&addr(1)), Some(-1));
+        assert_eq!(table.next_to_dial(now), None);
+        assert_eq!(table.next_to_dial(now + BASE_BACKOFF), Some(addr(1)));
+
+        table.failed(addr(1), now);
+        assert_eq!(table.next_to_dial(now + BASE_BACKOFF), None);
+        assert_eq!(table.next_to_dial(now + BASE_BACKOFF * 2), Some(addr(1)));
+
+        table.connected(addr(1));
+        table.disconnected(addr(1), now);
+        assert_eq!(table.next_to_dial(now + BASE_BACKOFF), Some(addr(1)));
+    }
+
+    #[test]
+    fn test_ban() {
+        let now = Instant::now();
+        let mut table = PeerTable::new();
+        table.add(addr(1));
+        table.add(addr(2));
+
+        table.ban(addr(1));
+        assert_eq!(table.addresses(10), vec![addr(2)]);
+        assert_eq!(table.next_to_dial(now + MAX_BACKOFF), Some(addr(2)));
+        assert_eq!(table.next_to_dial(now + MAX_BACKOFF), None);
+    }
+
+    #[test]
+    fn test_full() {
+        let now = Instant::now();
+        let mut table = PeerTable::new();
+
+        for port in 0..MAX_ADDRESSES as u16 {
+            table.add(addr(port));
+        }
+
+        // the worst scored goes first, a banned address stays banned.
+        table.ban(addr(5));
+        table.failed(addr(3), now);
+        table.failed(addr(4), now);
+        table.failed(addr(4), now);
+
+        assert!(table.add(addr(u16::MAX)));
+        assert!(!table.contains(&addr(4)));
+        assert!(table.add(addr(u16::MAX - 1)));
+        assert!(!table.contains(&addr(3)));
+        assert!(table.contains(&addr(5)));
+        assert!(!table.add(addr(5)));
+        assert_eq!(table.score(&addr(5)), Some(BAN_SCORE));
+        assert_eq!(table.len(), MAX_ADDRESSES);
+        assert_eq!(table.addresses(3), vec![addr(0), addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn test_full_keeps_good_addresses() {
+        let now = Instant::now();
+        let mut table = PeerTable::new();
+
+        for port in 0..MAX_ADDRESSES as u16 {
+            table.add(addr(port));
+            table.connected(addr(port));
+        }
+
+        // connected ones are never dropped, idle ones only until they ever worked.
+        table.disconnected(addr(0), now);
+        assert!(!table.add(addr(u16::MAX)));
+        assert_eq!(table.len(), MAX_ADDRESSES);
+        assert!(table.contains(&addr(0)));
+    }
+}
@@ -1,26 +1,198 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::io;
-use std::io::{ErrorKind, Read, Write};
-use rmp_serde::Serializer;
-use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::crypto::sha256;
 
-pub fn send<TWriter: Write, TData: Serialize>(mut writer: TWriter, data: &TData) -> io::Result<()> {
-    let mut ser = Serializer::new(&mut writer);
+/// the first bytes of every frame, a stream that does not start with them is not talking to us.
+pub const MAGIC: [u8; 4] = *b"crpt";
+/// the largest message body that is read, a block with a full template fits with room to spare.
+pub const MAX_MESSAGE_SIZE: u32 = 4 * 1024 * 1024;
 
-    match data.serialize(&mut ser) {
-        Ok(_) => {
-            writer.flush()?;
-            Ok(())
+/// magic, length and checksum, in front of every message.
+const HEADER_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    BadMagic { found: [u8; 4] },
+    TooLarge { len: u32 },
+    BadChecksum,
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl ProtocolError {
+    /// whether the stream can still be read after the error.
+    ///
+    /// the body of a frame is always read in full, so a corrupted or unknown message
+    /// can be skipped, but after a bad header the next frame can not be found any more.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, ProtocolError::BadChecksum | ProtocolError::Decode(_))
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "io error: {e}"),
+            ProtocolError::BadMagic { found } => write!(f, "invalid magic {}", hex::encode(found)),
+            ProtocolError::TooLarge { len } => write!(f, "message of {len} bytes is larger than {MAX_MESSAGE_SIZE}"),
+            ProtocolError::BadChecksum => write!(f, "checksum does not match the message"),
+            ProtocolError::Encode(e) => write!(f, "could not encode message: {e}"),
+            ProtocolError::Decode(e) => write!(f, "could not decode message: {e}"),
         }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
 
-        Err(e) => {
-            Err(io::Error::new(ErrorKind::Other, format!("Failed to serialize data: {:?}", e)))
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
         }
     }
 }
 
-pub fn recv<'de, TReader: Read, TData: Deserialize<'de>>(reader: &mut TReader) -> io::Result<TData> {
-    let mut de = rmp_serde::Deserializer::new(reader);
+fn checksum(body: &[u8]) -> [u8; 4] {
+    let hash = sha256::hash(body);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// writes `data` as one frame: magic, the big endian length of the body,
+/// the first 4 bytes of the body's sha256, then the MessagePack body.
+pub fn send<TWriter: Write, TData: Serialize>(mut writer: TWriter, data: &TData) -> Result<(), ProtocolError> {
+    let body = rmp_serde::to_vec(data).map_err(ProtocolError::Encode)?;
+
+    let len = u32::try_from(body.len())
+        .ok()
+        .filter(|len| *len <= MAX_MESSAGE_SIZE)
+        .ok_or(ProtocolError::TooLarge { len: body.len().try_into().unwrap_or(u32::MAX) })?;
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + body.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&checksum(&body));
+    frame.extend_from_slice(&body);
+
+    // one write, so frames from different threads do not interleave on a shared stream.
+    writer.write_all(&frame)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// reads one frame, never more than `MAX_MESSAGE_SIZE` bytes of body.
+pub fn recv<TReader: Read, TData: DeserializeOwned>(reader: &mut TReader) -> Result<TData, ProtocolError> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+
+    let magic: [u8; 4] = header[..4].try_into().unwrap();
+    if magic != MAGIC {
+        return Err(ProtocolError::BadMagic { found: magic });
+    }
+
+    let len = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if len > MAX_MESSAGE_SIZE {
+        return Err(ProtocolError::TooLarge { len });
+    }
+
+    let mut body = vec![0u8; len as usize];
+    reader.read_exact(&mut body)?;
+
+    if checksum(&body) != header[8..] {
+        return Err(ProtocolError::BadChecksum);
+    }
+
+    rmp_serde::from_slice(&body).map_err(ProtocolError::Decode)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::Message;
+    use super::*;
+
+    fn frame(msg: &Message) -> Vec<u8> {
+        let mut buf = Vec::new();
+        send(&mut buf, msg).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let msg = Message::Text("hello".into());
+        let buf = frame(&msg);
+
+        assert_eq!(buf[..4], MAGIC);
+        assert_eq!(u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize, buf.len() - HEADER_SIZE);
+        assert_eq!(recv::<_, Message>(&mut &buf[..]).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_skip_corrupted_message() {
+        let mut buf = frame(&Message::Text("hello".into()));
+        let last = buf.len() - 1;
+        buf[last] ^= 0x01;
 
-    Deserialize::deserialize(&mut de)
-        .map_err(|e| io::Error::new(ErrorKind::Other, format!("Failed to deserialize data: {:?}", e)))
+        // a body that does not decode to a message.
+        let mut garbage = frame(&Message::Echo);
+        garbage.truncate(HEADER_SIZE);
+        garbage[4..8].copy_from_slice(&1u32.to_be_bytes());
+        garbage[8..].copy_from_slice(&checksum(&[0xc1]));
+        garbage.push(0xc1);
+
+        buf.extend_from_slice(&garbage);
+        buf.extend_from_slice(&frame(&Message::Echo));
+
+        let mut reader = &buf[..];
+        let error = recv::<_, Message>(&mut reader).unwrap_err();
+        assert!(matches!(error, ProtocolError::BadChecksum));
+        assert!(error.is_recoverable());
+
+        let error = recv::<_, Message>(&mut reader).unwrap_err();
+        assert!(matches!(error, ProtocolError::Decode(_)));
+        assert!(error.is_recoverable());
+
+        // both bad frames were read in full, the next one is found.
+        assert_eq!(recv::<_, Message>(&mut reader).unwrap(), Message::Echo);
+    }
+
+    #[test]
+    fn test_reject_bad_header() {
+        let mut buf = frame(&Message::Echo);
+        buf[0] = b'x';
+        let error = recv::<_, Message>(&mut &buf[..]).unwrap_err();
+        assert!(matches!(error, ProtocolError::BadMagic { found } if &found == b"xrpt"));
+        assert!(!error.is_recoverable());
+
+        // the length is checked before anything is allocated or read.
+        let mut buf = frame(&Message::Echo);
+        buf[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(recv::<_, Message>(&mut &buf[..]), Err(ProtocolError::TooLarge { len: u32::MAX })));
+
+        let buf = frame(&Message::Text("hello".into()));
+        assert!(matches!(
+            recv::<_, Message>(&mut &buf[..buf.len() - 1]),
+            Err(ProtocolError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn test_send_too_large() {
+        let msg = Message::Text("x".repeat(MAX_MESSAGE_SIZE as usize));
+        let mut buf = Vec::new();
+
+        assert!(matches!(send(&mut buf, &msg), Err(ProtocolError::TooLarge { .. })));
+        assert!(buf.is_empty());
+    }
 }
@@ -0,0 +1,70 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+
+/// the last `capacity` distinct items inserted, the oldest is forgotten first.
+#[derive(Debug)]
+pub struct Seen<T> {
+    capacity: usize,
+    items: HashSet<T>,
+    order: VecDeque<T>,
+}
+
+impl<T: Hash + Eq + Copy> Seen<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            items: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+
+    /// remembers the item, returns whether it was not seen recently.
+    pub fn insert(&mut self, item: T) -> bool {
+        if !self.items.insert(item) {
+            return false;
+        }
+
+        self.order.push_back(item);
+
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.items.remove(&oldest);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut seen = Seen::new(2);
+
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(seen.insert(3));
+
+        // 1 was the oldest, it is new again.
+        assert_eq!(seen.len(), 2);
+        assert!(!seen.contains(&1));
+        assert!(seen.insert(1));
+        assert!(!seen.contains(&2));
+        assert!(seen.contains(&3));
+    }
+}
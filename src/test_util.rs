@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+use uuid::Uuid;
+use crate::block::Block;
+use crate::chain::Chain;
+use crate::miner;
+use crate::params::Params;
+use crate::transaction::Transaction;
+use crate::util::target::Target;
+use crate::wallet::Wallet;
+
+/// a directory of its own for every test, so they can run at the same time.
+pub fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("crypton_test_{}", Uuid::new_v4()))
+}
+
+/// an easy target, so the blocks of a test are mined right away.
+pub fn params() -> Params {
+    Params {
+        initial_bits: Target::from_leading_zero_bits(8).to_compact(),
+        ..Params::default()
+    }
+}
+
+/// mines a block with the transactions and a coinbase paying alice onto the chain.
+pub fn mine_block(chain: &mut Chain, transactions: &[Transaction]) -> Block {
+    let alice = Wallet::from_passphrase("alice");
+
+    let mut block = chain.next_block();
+    for txn in transactions {
+        block.add_transaction(txn);
+    }
+
+    block.set_coinbase(&alice.address, chain.params()).unwrap();
+    miner::mine(&mut block, chain.params().pow);
+    chain.append(&block).unwrap();
+
+    block
+}
+
+/// mines `count` blocks with only a coinbase in them onto the chain.
+pub fn mine_blocks(chain: &mut Chain, count: usize) {
+    for _ in 0..count {
+        mine_block(chain, &[]);
+    }
+}
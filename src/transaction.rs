@@ -1,57 +1,165 @@
-use std::fmt::{Debug, Formatter};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
 use std::time::UNIX_EPOCH;
 use secp256k1::ecdsa::Signature;
-use secp256k1::Message;
+use serde::{Deserialize, Serialize};
 use crate::crypto::sha256;
-use crate::wallet::Wallet;
+use crate::wallet;
+use crate::wallet::{Wallet, ADDRESS_SIZE};
 
-const PAYLOAD_SIZE: usize = 112;
+const PAYLOAD_SIZE: usize = 120;
 const EMPTY_HASH: [u8; 32] = [0u8; 32];
 const EMPTY_SIGN: [u8; 64] = [0u8; 64];
 
-#[derive(Clone)]
-pub struct Transaction<'a> {
-    pub sender: &'a Wallet,
-    pub recipient: &'a Wallet,
+/// the sender of every coinbase, no key hashes to it so it can never sign anything.
+pub const COINBASE_ADDRESS: &str = "0x000000000000000000000000000000000000000000";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    HashMismatch,
+    InvalidSender,
+    InvalidRecipient,
+    SenderMismatch,
+    InvalidSignature,
+    InvalidCoinbase,
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::HashMismatch => write!(f, "hash does not match the payload"),
+            TransactionError::InvalidSender => write!(f, "sender is not a valid address"),
+            TransactionError::InvalidRecipient => write!(f, "recipient is not a valid address"),
+            TransactionError::SenderMismatch => write!(f, "sender key does not belong to the sender address"),
+            TransactionError::InvalidSignature => write!(f, "signature is not valid for the sender key"),
+            TransactionError::InvalidCoinbase => write!(f, "coinbase carries a key, signature or fee"),
+        }
+    }
+}
+
+impl Error for TransactionError {}
+
+/// a transfer between two addresses.
+///
+/// only public data is kept, the sender's serialized public key is carried along
+/// so the signature can be checked by anyone who receives the transaction.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub sender: String,
+    pub sender_key: Vec<u8>,
+    pub recipient: String,
     pub amount: u64,
     pub fee: u64,
     pub timestamp: u64,
+    /// how many transactions the sender sent before this one, so it can only be applied once.
+    /// a coinbase carries the height of its block instead.
+    pub nonce: u64,
 
     pub hash: [u8; 32],
     pub signature: Signature,
 }
 
-impl<'a> Transaction<'a> {
-    pub fn new(sender: &'a Wallet, recipient: &'a Wallet, amount: u64, fee: u64) -> Self {
+impl Transaction {
+    pub fn new(sender: &Wallet, recipient: &str, amount: u64, fee: u64, nonce: u64) -> Self {
         let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs();
 
         let mut txn = Self {
-            sender,
-            recipient,
+            sender: sender.address.clone(),
+            sender_key: sender.ecdsa.p_key.serialize().to_vec(),
+            recipient: recipient.to_string(),
             amount,
             fee,
             timestamp,
+            nonce,
             hash: EMPTY_HASH,
             signature: Signature::from_compact(&EMPTY_SIGN).unwrap(),
         };
 
+        // the wallet hashes the payload before signing, the same way `verify` does.
         let payload = txn.get_payload();
-
         txn.hash = sha256::hash(payload);
-        let msg = Message::from_digest(txn.hash);
-        txn.signature = sender.ecdsa.sign(&msg);
+        txn.signature = sender.sign(&payload);
+
+        txn
+    }
+
+    /// creates new coins for `recipient`, only valid as the first transaction of a block.
+    pub fn coinbase(recipient: &str, amount: u64, height: u64) -> Self {
+        let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+        let mut txn = Self {
+            sender: COINBASE_ADDRESS.to_string(),
+            sender_key: Vec::new(),
+            recipient: recipient.to_string(),
+            amount,
+            fee: 0,
+            timestamp,
+            nonce: height,
+            hash: EMPTY_HASH,
+            signature: Signature::from_compact(&EMPTY_SIGN).unwrap(),
+        };
+
+        txn.hash = sha256::hash(txn.get_payload());
 
         txn
     }
 
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == COINBASE_ADDRESS
+    }
+
+    /// checks the transaction with nothing but the data it carries:
+    /// both addresses are well formed, the hash commits to the payload, the key belongs to the sender,
+    /// and the signature over the payload was made with that key.
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        // checked first, the payload only commits to addresses of the right length.
+        if !wallet::is_valid_address(&self.sender) {
+            return Err(TransactionError::InvalidSender);
+        }
+
+        if !wallet::is_valid_address(&self.recipient) {
+            return Err(TransactionError::InvalidRecipient);
+        }
+
+        let payload = self.get_payload();
+
+        if sha256::hash(payload) != self.hash {
+            return Err(TransactionError::HashMismatch);
+        }
+
+        // nothing signs a coinbase, where it may appear is up to the block.
+        if self.is_coinbase() {
+            let unsigned = self.sender_key.is_empty()
+                && self.fee == 0
+                && self.signature.serialize_compact() == EMPTY_SIGN;
+
+            return match unsigned {
+                true => Ok(()),
+                false => Err(TransactionError::InvalidCoinbase),
+            };
+        }
+
+        if !wallet::is_owner(&self.sender, &self.sender_key) {
+            return Err(TransactionError::SenderMismatch);
+        }
+
+        if !wallet::verify(&self.sender, &self.sender_key, &payload, &self.signature) {
+            return Err(TransactionError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+
+    /// the bytes the hash and the signature commit to.
+    ///
+    /// it is only unique for well formed addresses, `verify` rejects every other transaction
+    /// before looking at its payload.
     pub fn get_payload(&self) -> [u8; PAYLOAD_SIZE] {
         let mut buffer = [0u8; PAYLOAD_SIZE];
 
-        let sender_address = self.sender.address.as_bytes();
-        buffer[..44].copy_from_slice(sender_address);
+        copy_address(&mut buffer[..ADDRESS_SIZE], &self.sender);
 
-        let recipient_address = self.recipient.address.as_bytes();
-        buffer[44..88].copy_from_slice(recipient_address);
+        copy_address(&mut buffer[ADDRESS_SIZE..2 * ADDRESS_SIZE], &self.recipient);
 
         let amount = self.amount.to_be_bytes();
         buffer[88..96].copy_from_slice(&amount);
@@ -62,20 +170,35 @@ impl<'a> Transaction<'a> {
         let timestamp = self.timestamp.to_be_bytes();
         buffer[104..112].copy_from_slice(&timestamp);
 
+        let nonce = self.nonce.to_be_bytes();
+        buffer[112..120].copy_from_slice(&nonce);
+
         buffer
     }
 }
 
-impl<'a> Debug for Transaction<'a> {
+/// copies a well formed address, anything else is left as zeros instead of panicking,
+/// such a transaction never passes `verify`.
+fn copy_address(buffer: &mut [u8], address: &str) {
+    if !wallet::is_valid_address(address) {
+        return;
+    }
+
+    buffer.copy_from_slice(address.as_bytes());
+}
+
+impl Debug for Transaction {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Transaction")
-            .field("sender", &self.sender.address)
-            .field("recipient", &self.recipient.address)
+            .field("sender", &self.sender)
+            .field("sender_key", &hex::encode(&self.sender_key))
+            .field("recipient", &self.recipient)
             .field("amount", &self.amount)
             .field("fee", &self.fee)
             .field("timestamp", &self.timestamp)
+            .field("nonce", &self.nonce)
             .field("hash", &sha256::digest(&self.hash))
-            .field("signature", &hex::encode(&self.signature.serialize_compact()))
+            .field("signature", &hex::encode(self.signature.serialize_compact()))
             .finish()
     }
 }
@@ -89,7 +212,7 @@ mod tests {
         let s = Wallet::from_passphrase("bob");
         let r = Wallet::from_passphrase("alice");
 
-        let txn = Transaction::new(&s, &r, 100, 1);
+        let txn = Transaction::new(&s, &r.address, 100, 1, 0);
 
         println!("{:#?}", txn);
     }
@@ -99,7 +222,7 @@ mod tests {
         let s = Wallet::from_passphrase("bob");
         let r = Wallet::from_passphrase("alice");
 
-        let txn = Transaction::new(&s, &r, 100, 1);
+        let txn = Transaction::new(&s, &r.address, 100, 1, 0);
 
         let payload = txn.get_payload();
         let hash = sha256::hash(payload);
@@ -112,8 +235,144 @@ mod tests {
         let s = Wallet::from_passphrase("bob");
         let r = Wallet::from_passphrase("alice");
 
-        let t = Transaction::new(&s, &r, 100, 1);
+        let t = Transaction::new(&s, &r.address, 100, 1, 0);
+
+        assert_eq!(t.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_wrong_sender() {
+        let s = Wallet::from_passphrase("bob");
+        let r = Wallet::from_passphrase("alice");
+
+        // the sender's own key in its uncompressed form is not accepted.
+        let mut t = Transaction::new(&s, &r.address, 100, 1, 0);
+        t.sender_key = s.ecdsa.p_key.serialize_uncompressed().to_vec();
+        assert_eq!(t.verify(), Err(TransactionError::SenderMismatch));
+
+        let mut t = Transaction::new(&s, &r.address, 100, 1, 0);
+        t.sender = r.address.clone();
+        t.hash = sha256::hash(t.get_payload());
+        assert_eq!(t.verify(), Err(TransactionError::SenderMismatch));
+
+        // claiming to be alice with alice's key still needs alice's signature.
+        t.sender_key = r.ecdsa.p_key.serialize().to_vec();
+        assert_eq!(t.verify(), Err(TransactionError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_coinbase() {
+        let r = Wallet::from_passphrase("alice");
+
+        let mut t = Transaction::coinbase(&r.address, 50, 0);
+        assert!(t.is_coinbase());
+        assert_eq!(t.verify(), Ok(()));
+
+        t.fee = 1;
+        t.hash = sha256::hash(t.get_payload());
+        assert_eq!(t.verify(), Err(TransactionError::InvalidCoinbase));
+    }
+
+    #[test]
+    fn test_verify_malformed_recipient() {
+        let s = Wallet::from_passphrase("bob");
+
+        let t = Transaction::new(&s, "0x1234", 100, 1, 0);
+        assert_eq!(t.verify(), Err(TransactionError::InvalidRecipient));
+
+        // an address that is a character too long or too short is rejected, not cut to size.
+        let r = Wallet::from_passphrase("alice");
+        let mut t = Transaction::new(&s, &r.address, 100, 1, 0);
+        t.recipient.push('0');
+        assert_eq!(t.verify(), Err(TransactionError::InvalidRecipient));
+
+        let mut t = Transaction::new(&s, &r.address, 100, 1, 0);
+        t.sender.pop();
+        assert_eq!(t.verify(), Err(TransactionError::InvalidSender));
+    }
+
+    #[test]
+    fn test_serde() {
+        let s = Wallet::from_passphrase("bob");
+        let r = Wallet::from_passphrase("alice");
+
+        let txn = Transaction::new(&s, &r.address, 100, 1, 0);
+
+        let buf = rmp_serde::to_vec(&txn).unwrap();
+        let decoded: Transaction = rmp_serde::from_slice(&buf).unwrap();
+
+        assert_eq!(txn, decoded);
+        assert_eq!(txn.get_payload(), decoded.get_payload());
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use once_cell::sync::Lazy;
+    use proptest::prelude::*;
+    use super::*;
+
+    static BOB: Lazy<Wallet> = Lazy::new(|| Wallet::from_passphrase("bob"));
+    static ALICE: Lazy<Wallet> = Lazy::new(|| Wallet::from_passphrase("alice"));
+    static EVE: Lazy<Wallet> = Lazy::new(|| Wallet::from_passphrase("eve"));
+
+    fn signed(amount: u64, fee: u64) -> Transaction {
+        Transaction::new(&BOB, &ALICE.address, amount, fee, 0)
+    }
+
+    /// a tampered field is caught by the hash, and re-hashing it is caught by the signature.
+    fn assert_rejected(mut txn: Transaction) {
+        assert_eq!(txn.verify(), Err(TransactionError::HashMismatch));
 
-        assert!(s.verify(&t.get_payload(), &t.signature));
+        txn.hash = sha256::hash(txn.get_payload());
+        assert_eq!(txn.verify(), Err(TransactionError::InvalidSignature));
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn untampered_verifies(amount: u64, fee: u64) {
+            prop_assert_eq!(signed(amount, fee).verify(), Ok(()));
+        }
+
+        #[test]
+        fn tampered_amount_rejected(amount: u64, fee: u64, other: u64) {
+            prop_assume!(amount != other);
+            let mut txn = signed(amount, fee);
+            txn.amount = other;
+            assert_rejected(txn);
+        }
+
+        #[test]
+        fn tampered_fee_rejected(amount: u64, fee: u64, other: u64) {
+            prop_assume!(fee != other);
+            let mut txn = signed(amount, fee);
+            txn.fee = other;
+            assert_rejected(txn);
+        }
+
+        #[test]
+        fn tampered_timestamp_rejected(amount: u64, fee: u64, other: u64) {
+            let mut txn = signed(amount, fee);
+            prop_assume!(txn.timestamp != other);
+            txn.timestamp = other;
+            assert_rejected(txn);
+        }
+
+        #[test]
+        fn tampered_nonce_rejected(amount: u64, fee: u64, other: u64) {
+            prop_assume!(other != 0);
+            let mut txn = signed(amount, fee);
+            txn.nonce = other;
+            assert_rejected(txn);
+        }
+
+        #[test]
+        fn tampered_recipient_rejected(amount: u64, fee: u64) {
+            let mut txn = signed(amount, fee);
+            txn.recipient = EVE.address.clone();
+            assert_rejected(txn);
+        }
+    }
+}
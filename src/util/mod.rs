@@ -1,2 +1,2 @@
 pub mod mrkl_root;
-pub mod hash_ring;
+pub mod target;
@@ -1,39 +1,142 @@
+use serde::{Deserialize, Serialize};
 use crate::crypto::sha256;
 
 const EMPTY: [u8; 32] = [0u8; 32];
 
+// every kind of node is hashed with its own prefix, so no node can pass for another:
+// a leaf for an inner node, or a pair of equal nodes for a node without a sibling.
+const LEAF: u8 = 0x00;
+const PAIR: u8 = 0x01;
+const SINGLE: u8 = 0x02;
+
+fn hash_leaf(leaf: &[u8; 32]) -> [u8; 32] {
+    let mut buffer = [0u8; 33];
+
+    buffer[0] = LEAF;
+    buffer[1..33].copy_from_slice(leaf);
+
+    sha256::hash(buffer)
+}
+
 fn hash_pair(lhs: &[u8; 32], rhs: &[u8; 32]) -> [u8; 32] {
-    let mut buffer = [0u8; 64];
+    let mut buffer = [0u8; 65];
 
-    buffer[0..32].copy_from_slice(lhs);
-    buffer[32..64].copy_from_slice(rhs);
+    buffer[0] = PAIR;
+    buffer[1..33].copy_from_slice(lhs);
+    buffer[33..65].copy_from_slice(rhs);
 
     sha256::hash(buffer)
 }
 
-pub fn calculate_mrkl_root(leaves: &[[u8; 32]]) -> [u8; 32] {
-    let mut leaves = Vec::from(leaves);
+/// the last node of a level with an odd number of nodes.
+///
+/// it used to be paired with itself, which gave `[a, b, c]` and `[a, b, c, c]` the same root.
+fn hash_single(node: &[u8; 32]) -> [u8; 32] {
+    let mut buffer = [0u8; 33];
+
+    buffer[0] = SINGLE;
+    buffer[1..33].copy_from_slice(node);
 
-    if leaves.len() == 0 {
+    sha256::hash(buffer)
+}
+
+/// the level above, every pair hashed together.
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|nodes| match nodes {
+            [lhs, rhs] => hash_pair(lhs, rhs),
+            [node] => hash_single(node),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn calculate_mrkl_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
         return EMPTY;
     }
 
-    loop {
-        match leaves[..] {
-            [first] => return first,
-            [.., last] if leaves.len() % 2 == 1 => {
-                leaves.push(last);
-                continue;
-            }
-            _ => {
-                leaves = (0..leaves.len())
-                    .filter(|i| i % 2 == 0)
-                    .map(|i| hash_pair(&leaves[i], &leaves[i + 1]))
-                    .into_iter()
-                    .collect();
-            }
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(hash_leaf).collect();
+
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    level[0]
+}
+
+/// the hashes on the way from a leaf up to the root, which prove the leaf is in the tree
+/// to someone who only knows the root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MrklBranch {
+    /// the position of the leaf, its lowest bit says which side the first sibling goes on, and so on upwards.
+    pub index: u64,
+    /// how many leaves the tree has, which says where a node has no sibling.
+    pub leaves: u64,
+    /// the sibling of every node on the way up that has one.
+    pub hashes: Vec<[u8; 32]>,
+}
+
+impl MrklBranch {
+    /// the root of the tree the branch was taken from, if `leaf` is the leaf at its index.
+    /// none if the branch does not fit a tree with that many leaves.
+    pub fn root(&self, leaf: &[u8; 32]) -> Option<[u8; 32]> {
+        if self.index >= self.leaves {
+            return None;
         }
+
+        let mut hashes = self.hashes.iter();
+        let mut node = hash_leaf(leaf);
+        let mut position = self.index;
+        let mut width = self.leaves;
+
+        while width > 1 {
+            node = if position ^ 1 >= width {
+                hash_single(&node)
+            } else if position.is_multiple_of(2) {
+                hash_pair(&node, hashes.next()?)
+            } else {
+                hash_pair(hashes.next()?, &node)
+            };
+
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+
+        // left over hashes belong to some other tree.
+        hashes.next().is_none().then_some(node)
     }
+
+    pub fn verify(&self, leaf: &[u8; 32], root: &[u8; 32]) -> bool {
+        self.root(leaf) == Some(*root)
+    }
+}
+
+/// the branch proving the leaf at `index` is in the tree of `leaves`, none if there is no such leaf.
+pub fn calculate_mrkl_branch(leaves: &[[u8; 32]], index: usize) -> Option<MrklBranch> {
+    if index >= leaves.len() {
+        return None;
+    }
+
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(hash_leaf).collect();
+    let mut position = index;
+    let mut hashes = Vec::new();
+
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(position ^ 1) {
+            hashes.push(*sibling);
+        }
+
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MrklBranch {
+        index: index as u64,
+        leaves: leaves.len() as u64,
+        hashes,
+    })
 }
 
 #[cfg(test)]
@@ -52,7 +155,59 @@ mod tests {
         let mrkl_root = calculate_mrkl_root(&txn_hashes);
         let mrkl_root_digest = sha256::digest(&mrkl_root);
 
-        let expected = "20d91ce8e5b46488788bee6b7b2dec6216168c5bf2e1dc484be420bad8462aa9";
+        // sha256(0x01 || sha256(0x01 || leaf(aaa) || leaf(bbb)) || sha256(0x01 || leaf(ccc) || leaf(ddd))),
+        // with leaf(x) = sha256(0x00 || sha256(x)).
+        let expected = "4e5360e36c2cb56b56f3ef1be0c3368736448b8f6b044fe83026b8ce9b3e0e64";
         assert_eq!(mrkl_root_digest, expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mrkl_branch() {
+        for count in 1..=9u8 {
+            let leaves: Vec<[u8; 32]> = (0..count).map(|i| sha256::hash([i])).collect();
+            let root = calculate_mrkl_root(&leaves);
+
+            for (index, leaf) in leaves.iter().enumerate() {
+                let branch = calculate_mrkl_branch(&leaves, index).unwrap();
+                assert!(branch.verify(leaf, &root), "{count} leaves, index {index}");
+
+                let other = sha256::hash(b"other");
+                assert!(!branch.verify(&other, &root));
+
+                // the same hashes with the leaf on the other side give another root.
+                if index ^ 1 < leaves.len() {
+                    let swapped = MrklBranch { index: index as u64 ^ 1, ..branch.clone() };
+                    assert!(!swapped.verify(leaf, &root));
+                }
+
+                let beyond = MrklBranch { index: count as u64, ..branch.clone() };
+                assert!(!beyond.verify(leaf, &root));
+
+                let mut longer = branch.clone();
+                longer.hashes.push(root);
+                assert!(!longer.verify(leaf, &root));
+
+                // the last leaf of an odd tree can not claim to be its own missing sibling.
+                let phantom = MrklBranch { index: index as u64 ^ 1, leaves: branch.leaves + 1, ..branch };
+                assert!(!phantom.verify(leaf, &root));
+            }
+
+            assert_eq!(calculate_mrkl_branch(&leaves, count as usize), None);
+        }
+    }
+
+    #[test]
+    fn test_mutated_leaves_do_not_collide() {
+        let [a, b, c, d, e, f] = [b"a", b"b", b"c", b"d", b"e", b"f"].map(sha256::hash);
+
+        // the last leaves repeated, which used to give the same root.
+        assert_ne!(calculate_mrkl_root(&[a, b, c]), calculate_mrkl_root(&[a, b, c, c]));
+        assert_ne!(calculate_mrkl_root(&[a, b, c, d, e, f]), calculate_mrkl_root(&[a, b, c, d, e, f, e, f]));
+        assert_ne!(calculate_mrkl_root(&[a]), calculate_mrkl_root(&[a, a]));
+
+        // an inner node passed off as a leaf.
+        let ab = hash_pair(&hash_leaf(&a), &hash_leaf(&b));
+        let cd = hash_pair(&hash_leaf(&c), &hash_leaf(&d));
+        assert_ne!(calculate_mrkl_root(&[a, b, c, d]), calculate_mrkl_root(&[ab, cd]));
+    }
+}
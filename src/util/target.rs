@@ -0,0 +1,189 @@
+use std::fmt::{Debug, Formatter};
+
+/// the number a block hash has to be at most, as a 256 bit big endian integer like the hash itself.
+///
+/// headers store it in the compact form bitcoin uses: the top byte is the size of the number in bytes,
+/// the low 3 bytes are its most significant bytes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target([u8; 32]);
+
+impl Target {
+    pub const MAX: Target = Target([0xff; 32]);
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+
+    /// the target that accepts every hash starting with at least `bits` zero bits.
+    pub fn from_leading_zero_bits(bits: u32) -> Self {
+        let mut bytes = [0xff; 32];
+
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let zeros = bits.saturating_sub(i as u32 * 8).min(8);
+            *byte = (0xffu16 >> zeros) as u8;
+        }
+
+        Self(bytes)
+    }
+
+    pub fn leading_zero_bits(self) -> u32 {
+        let mut zeros = 0;
+
+        for byte in self.0 {
+            zeros += byte.leading_zeros();
+            if byte != 0 {
+                break;
+            }
+        }
+
+        zeros
+    }
+
+    pub fn from_compact(bits: u32) -> Self {
+        let size = (bits >> 24) as usize;
+        // the sign bit, a negative target accepts nothing.
+        if bits & 0x0080_0000 != 0 {
+            return Self([0u8; 32]);
+        }
+
+        let mantissa = (bits & 0x007f_ffff).to_be_bytes();
+        let mut bytes = [0u8; 32];
+
+        // the mantissa's bytes land at `32 - size` onwards, anything shifted out below is dropped.
+        for (i, byte) in mantissa[1..].iter().enumerate() {
+            let position = 32 + i as isize - size as isize;
+
+            if position >= 32 || *byte == 0 {
+                continue;
+            }
+
+            if position < 0 {
+                return Self::MAX;
+            }
+
+            bytes[position as usize] = *byte;
+        }
+
+        Self(bytes)
+    }
+
+    /// the compact form, rounded down to the 3 most significant bytes.
+    pub fn to_compact(self) -> u32 {
+        let Some(first) = self.0.iter().position(|byte| *byte != 0) else {
+            return 0;
+        };
+
+        let mut size = 32 - first;
+
+        // the mantissa is left aligned, small numbers are padded with zeros.
+        let mut mantissa = [0u8; 4];
+        for i in 0..3 {
+            mantissa[i + 1] = self.0.get(first + i).copied().unwrap_or(0);
+        }
+        let mut mantissa = u32::from_be_bytes(mantissa);
+
+        // keep the sign bit clear.
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        ((size as u32) << 24) | mantissa
+    }
+
+    pub fn is_met_by(self, hash: &[u8; 32]) -> bool {
+        *hash <= self.0
+    }
+
+    /// `self * mul / div`, saturating at `Target::MAX`.
+    pub fn mul_div(self, mul: u64, div: u64) -> Self {
+        let div = div.max(1) as u128;
+
+        // 64 bit limbs, most significant first, with one extra for the overflow of the multiplication.
+        let mut limbs = [0u64; 5];
+        for (i, chunk) in self.0.chunks(8).enumerate() {
+            limbs[i + 1] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+
+        let mut carry = 0u128;
+        for limb in limbs.iter_mut().rev() {
+            let product = *limb as u128 * mul as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+
+        let mut remainder = 0u128;
+        for limb in limbs.iter_mut() {
+            let current = (remainder << 64) | *limb as u128;
+            *limb = (current / div) as u64;
+            remainder = current % div;
+        }
+
+        if limbs[0] != 0 {
+            return Self::MAX;
+        }
+
+        let mut bytes = [0u8; 32];
+        for (i, limb) in limbs[1..].iter().enumerate() {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&limb.to_be_bytes());
+        }
+
+        Self(bytes)
+    }
+}
+
+impl Debug for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Target({})", hex::encode(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact() {
+        // the bitcoin genesis target.
+        let target = Target::from_compact(0x1d00ffff);
+        assert_eq!(
+            hex::encode(target.to_bytes()),
+            "00000000ffff0000000000000000000000000000000000000000000000000000"
+        );
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+
+        assert_eq!(Target::from_compact(0x03123456).to_bytes()[29..], [0x12, 0x34, 0x56]);
+        assert_eq!(Target::from_compact(0x02123456).to_bytes()[30..], [0x12, 0x34]);
+        assert_eq!(Target::from_compact(0x01123456).to_compact(), 0x01120000);
+        assert_eq!(Target::from_compact(0x02008000).to_compact(), 0x02008000);
+        assert_eq!(Target::from_compact(0x00800000), Target::from_bytes([0u8; 32]));
+        assert_eq!(Target::from_compact(0x2200ffff), Target::MAX);
+        assert_eq!(Target::MAX.to_compact(), 0x2100ffff);
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        for bits in [0, 1, 7, 8, 9, 20, 255] {
+            let target = Target::from_leading_zero_bits(bits);
+            assert_eq!(target.leading_zero_bits(), bits);
+        }
+
+        let target = Target::from_leading_zero_bits(12);
+        assert!(target.is_met_by(&[0x00, 0x0f, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(!target.is_met_by(&[0x00, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_mul_div() {
+        let target = Target::from_compact(0x1d00ffff);
+
+        assert_eq!(target.mul_div(4, 1).to_compact(), 0x1d03fffc);
+        assert_eq!(target.mul_div(1, 4).to_compact(), 0x1c3fffc0);
+        assert_eq!(target.mul_div(3, 3), target);
+        assert_eq!(Target::from_leading_zero_bits(1).mul_div(4, 1), Target::MAX);
+    }
+}
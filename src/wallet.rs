@@ -1,30 +1,26 @@
 use secp256k1::ecdsa::Signature;
-use secp256k1::Message;
+use secp256k1::constants::PUBLIC_KEY_SIZE;
+use secp256k1::{Message, PublicKey};
+use crate::crypto::ecdsa;
 use crate::crypto::ecdsa::Ecdsa;
 use crate::crypto::sha256;
 
+/// `0x` followed by 42 hex characters.
+pub const ADDRESS_SIZE: usize = 44;
+
 #[derive(Debug)]
 pub struct Wallet {
     pub ecdsa: Ecdsa,
-    pub balance: u64,
     pub address: String,
 }
 
 impl Wallet {
     pub fn new(ecdsa: Ecdsa) -> Self {
-        let address = {
-            let p_key_bytes = ecdsa.p_key.serialize();
-
-            let hash = sha256::hash(&p_key_bytes);
-            let digest = sha256::digest(&hash);
-
-            format!("0x{}", digest[22..64].to_uppercase().to_string())
-        };
+        let address = address_of(&ecdsa.p_key);
 
         Self {
             ecdsa,
             address,
-            balance: 0,
         }
     }
 
@@ -50,10 +46,58 @@ impl Wallet {
             // because hash is always 32 bytes long
             .unwrap();
 
-        self.ecdsa.verify(&msg, &sig)
+        self.ecdsa.verify(&msg, sig)
     }
 }
 
+pub fn address_of(p_key: &PublicKey) -> String {
+    let p_key_bytes = p_key.serialize();
+
+    let hash = sha256::hash(p_key_bytes);
+    let digest = sha256::digest(&hash);
+
+    format!("0x{}", digest[22..64].to_uppercase())
+}
+
+pub fn is_valid_address(address: &str) -> bool {
+    address.len() == ADDRESS_SIZE
+        && address.starts_with("0x")
+        && address[2..].chars().all(|c| matches!(c, '0'..='9' | 'A'..='F'))
+}
+
+/// checks that the serialized public key hashes to `address`.
+///
+/// only the compressed form is accepted, the key is not part of the transaction payload,
+/// so a second encoding of it would give the same transaction a second form.
+pub fn is_owner(address: &str, p_key: &[u8]) -> bool {
+    if p_key.len() != PUBLIC_KEY_SIZE {
+        return false;
+    }
+
+    match PublicKey::from_slice(p_key) {
+        Ok(key) => address_of(&key) == address,
+        Err(_) => false,
+    }
+}
+
+/// verifies `data` was signed by the owner of `address`, using only their serialized public key.
+///
+/// the key must hash to the claimed address, otherwise anyone could sign with their own key
+/// and claim to be someone else.
+pub fn verify(address: &str, p_key: &[u8], data: &[u8], sig: &Signature) -> bool {
+    if !is_owner(address, p_key) {
+        return false;
+    }
+
+    let hash = sha256::hash(data);
+
+    let msg = Message::from_digest_slice(&hash)
+        // because hash is always 32 bytes long
+        .unwrap();
+
+    ecdsa::verify(p_key, &msg, sig)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,7 +118,31 @@ mod tests {
         for _ in 0..10 {
             let wallet = Wallet::from_passphrase("test phone elliptic curve");
             println!("{}", wallet.address);
+            assert!(is_valid_address(&wallet.address));
         }
+
+        assert!(!is_valid_address("0x1234"));
+        assert!(!is_valid_address(&"0x".repeat(22)));
+    }
+
+    #[test]
+    fn test_verify_public_key() {
+        let wallet = Wallet::from_passphrase("alice");
+        let other = Wallet::from_passphrase("bob");
+        let p_key = wallet.ecdsa.p_key.serialize();
+
+        let data = "test".as_bytes();
+        let sig = wallet.sign(data);
+
+        assert!(verify(&wallet.address, &p_key, data, &sig));
+        assert!(!verify(&wallet.address, &p_key, "tset".as_bytes(), &sig));
+        assert!(!verify(&other.address, &p_key, data, &sig));
+        assert!(!verify(&other.address, &other.ecdsa.p_key.serialize(), data, &sig));
+        assert!(!verify(&wallet.address, &p_key[1..], data, &sig));
+
+        let uncompressed = wallet.ecdsa.p_key.serialize_uncompressed();
+        assert!(!is_owner(&wallet.address, &uncompressed));
+        assert!(!verify(&wallet.address, &uncompressed, data, &sig));
     }
 
     #[test]